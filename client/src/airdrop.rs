@@ -0,0 +1,101 @@
+use std::str::FromStr;
+
+use anyhow::Result;
+use solana_client::{client_error::ClientError, nonblocking::rpc_client::RpcClient};
+use solana_sdk::{commitment_config::CommitmentConfig, pubkey::Pubkey};
+
+use crate::{
+    config::load_config,
+    retry::{RetryPolicy, is_transient},
+    util::{bounded_map, check_success_rate, format_sol, resolve_rpc_url, sol_to_lamports},
+};
+
+// Requests and confirms an airdrop for every wallet in the list, then reports the outcomes
+pub async fn airdrop_all(
+    wallets_file: String,
+    sol: f64,
+    max_concurrency: usize,
+    min_success_rate: Option<f64>,
+    rpc_url: Option<&str>,
+    retry: RetryPolicy,
+) -> Result<()> {
+    let wallets: Vec<String> = load_config(&wallets_file).await?;
+    let lamports = sol_to_lamports(sol)?;
+
+    // Connect to Solana network
+    let rpc_url = resolve_rpc_url(rpc_url)?;
+    let rpc_client = RpcClient::new_with_commitment(rpc_url, CommitmentConfig::confirmed());
+
+    println!(
+        "Airdropping {} SOL to {} wallets from {wallets_file}",
+        format_sol(lamports),
+        wallets.len()
+    );
+    let results = bounded_map(wallets, max_concurrency, |wallet| {
+        let rpc_client = &rpc_client;
+        async move {
+            let result = async {
+                let pubkey = Pubkey::from_str(&wallet)?;
+                airdrop(rpc_client, &pubkey, lamports, retry).await?;
+                Result::<_, anyhow::Error>::Ok(rpc_client.get_balance(&pubkey).await?)
+            }
+            .await;
+            (wallet, result)
+        }
+    })
+    .await?;
+
+    println!("\n{:<44} {:<8} {:>20}", "Wallet", "Status", "Balance (SOL)");
+    println!("{}", "-".repeat(74));
+    let mut failures = Vec::new();
+    for (wallet, result) in &results {
+        match result {
+            Ok(balance) => println!("{wallet:<44} {:<8} {:>20}", "ok", format_sol(*balance)),
+            Err(err) => {
+                println!("{wallet:<44} {:<8} {:>20}", "failed", "-");
+                failures.push((wallet, err));
+            }
+        }
+    }
+
+    println!(
+        "\nFunded {} of {} wallets",
+        results.len() - failures.len(),
+        results.len()
+    );
+    if !failures.is_empty() {
+        println!("\nFailed airdrops:");
+        for (wallet, err) in &failures {
+            println!("{wallet} - {err}");
+        }
+    }
+
+    check_success_rate(
+        results.len() - failures.len(),
+        results.len(),
+        min_success_rate,
+    )
+}
+
+// Requests an airdrop and waits for it to confirm, backing off while the faucet rate-limits
+pub async fn airdrop(
+    rpc_client: &RpcClient,
+    pubkey: &Pubkey,
+    lamports: u64,
+    retry: RetryPolicy,
+) -> Result<()> {
+    let signature = retry
+        .run(
+            || rpc_client.request_airdrop(pubkey, lamports),
+            |err| is_transient(err) || is_rate_limited(err),
+        )
+        .await?;
+    rpc_client.poll_for_signature(&signature).await?;
+    Ok(())
+}
+
+// Faucets answer with HTTP 429 or a JSON-RPC error mentioning the limit
+fn is_rate_limited(err: &ClientError) -> bool {
+    let message = err.to_string().to_lowercase();
+    message.contains("429") || message.contains("rate limit") || message.contains("airdrop limit")
+}
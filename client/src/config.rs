@@ -0,0 +1,106 @@
+use std::path::Path;
+
+use anyhow::{Context, Result, bail};
+use serde::de::DeserializeOwned;
+use tokio::fs;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ConfigFormat {
+    Yaml,
+    Json,
+    Toml,
+}
+
+impl ConfigFormat {
+    fn from_extension(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()?.to_ascii_lowercase().as_str() {
+            "yaml" | "yml" => Some(Self::Yaml),
+            "json" => Some(Self::Json),
+            "toml" => Some(Self::Toml),
+            _ => None,
+        }
+    }
+
+    // Guesses the format from the first meaningful line, YAML being the fallback
+    fn sniff(content: &str) -> Self {
+        let first_line = content
+            .lines()
+            .map(str::trim)
+            .find(|line| !line.is_empty() && !line.starts_with('#'))
+            .unwrap_or_default();
+
+        if first_line.starts_with('{') {
+            return Self::Json;
+        }
+        if let Some(header) = first_line.strip_prefix('[') {
+            // `[table]` and `[[array]]` headers only contain bare key characters
+            let name = header.trim_matches(|c| c == '[' || c == ']');
+            let is_toml_header = header.ends_with(']')
+                && !name.is_empty()
+                && name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'));
+            return if is_toml_header {
+                Self::Toml
+            } else {
+                Self::Json
+            };
+        }
+        match (first_line.find('='), first_line.find(':')) {
+            (Some(eq), Some(colon)) if eq < colon => Self::Toml,
+            (Some(_), None) => Self::Toml,
+            _ => Self::Yaml,
+        }
+    }
+
+    fn parse<T: DeserializeOwned>(self, content: &str) -> Result<T> {
+        Ok(match self {
+            Self::Yaml => serde_yaml::from_str(content)?,
+            Self::Json => serde_json::from_str(content)?,
+            Self::Toml => toml::from_str(content)?,
+        })
+    }
+
+    // Same as `parse`, but fails on fields the target type doesn't know
+    fn parse_strict<T: DeserializeOwned>(self, content: &str) -> Result<T> {
+        let mut unknown = Vec::new();
+        let on_ignored = |path: serde_ignored::Path| unknown.push(path.to_string());
+        let value = match self {
+            Self::Yaml => {
+                serde_ignored::deserialize(serde_yaml::Deserializer::from_str(content), on_ignored)?
+            }
+            Self::Json => serde_ignored::deserialize(
+                &mut serde_json::Deserializer::from_str(content),
+                on_ignored,
+            )?,
+            Self::Toml => {
+                serde_ignored::deserialize(toml::Deserializer::parse(content)?, on_ignored)?
+            }
+        };
+        if !unknown.is_empty() {
+            bail!("unknown fields: {}", unknown.join(", "));
+        }
+        Ok(value)
+    }
+}
+
+// Loads a YAML, JSON or TOML config, picking the format by file extension and
+// falling back to sniffing the content. Unknown fields, e.g. annotations, are ignored.
+pub async fn load_config<T: DeserializeOwned>(path: &str) -> Result<T> {
+    load_config_with(path, false).await
+}
+
+// Same as `load_config`, rejecting unknown fields when `strict` is set
+pub async fn load_config_with<T: DeserializeOwned>(path: &str, strict: bool) -> Result<T> {
+    let content = fs::read_to_string(path)
+        .await
+        .with_context(|| format!("failed to read {path}"))?;
+    let format = ConfigFormat::from_extension(Path::new(path))
+        .unwrap_or_else(|| ConfigFormat::sniff(&content));
+    let config = if strict {
+        format.parse_strict(&content)
+    } else {
+        format.parse(&content)
+    };
+    config.with_context(|| format!("failed to parse {path} as {format:?}"))
+}
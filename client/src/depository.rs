@@ -1,41 +1,89 @@
-use anyhow::{Result, bail};
-
-use borsh::{BorshDeserialize, BorshSerialize};
-use solana_client::nonblocking::rpc_client::RpcClient;
-use solana_program::{
-    instruction::{AccountMeta, Instruction},
-    pubkey::Pubkey,
-    system_instruction,
+use anyhow::{Result, anyhow, bail};
+use borsh::BorshDeserialize;
+use clap::Subcommand;
+use program::state::DepositAccount;
+use serde::Serialize;
+
+use futures::{StreamExt, stream};
+use solana_client::{
+    nonblocking::rpc_client::RpcClient, rpc_config::RpcProgramAccountsConfig,
+    rpc_filter::RpcFilterType,
 };
+use solana_program::pubkey::Pubkey;
+// Loader v4 isn't enabled on every cluster yet, so programs still go through the
+// upgradeable loader
+#[allow(deprecated)]
+use solana_sdk::bpf_loader_upgradeable::{self, UpgradeableLoaderState};
 use solana_sdk::{
-    bpf_loader_upgradeable,
     commitment_config::CommitmentConfig,
+    native_token::LAMPORTS_PER_SOL,
     signature::{Keypair, Signer},
-    transaction::Transaction,
 };
 use std::str::FromStr;
+use std::time::Duration;
 use std::{env, fs, io, path::Path};
+use tokio::{signal, time::sleep};
+
+use crate::{
+    instructions::{change_owner_ix, deposit_ix, initialize_ix, withdraw_ix},
+    priority::PriorityFee,
+    retry::RetryPolicy,
+    send::{SendOptions, send_resilient},
+    util::{
+        bounded_map, check_success_rate, format_sol, load_keypair, resolve_rpc_url, sol_to_lamports,
+    },
+};
 
-// Instructions recognized by the program
-#[derive(BorshSerialize, BorshDeserialize, Debug)]
-enum DepositInstruction {
-    Initialize,
-    Deposit,
-    Withdraw { amount: u64 },
+// What `depository` does once the payer and program are set up
+#[derive(Clone, Copy, Debug, Subcommand)]
+pub enum DepositoryAction {
+    /// Initialize the payer's deposit account.
+    Init,
+    /// Deposit SOL into the payer's deposit account.
+    Deposit {
+        #[clap(long)]
+        sol: f64,
+    },
+    /// Withdraw SOL from the payer's deposit account.
+    Withdraw {
+        #[clap(long)]
+        sol: f64,
+    },
+    /// Print the deposit account's lamports and stored balance.
+    Balance,
+    /// Choose operations from a menu until exit.
+    Interactive,
 }
 
-pub async fn depository() -> Result<()> {
+// Program bytes per buffer write, leaving room for the signature, account keys and
+// instruction header in a 1232 byte transaction
+const WRITE_CHUNK_SIZE: usize = 900;
+
+// Buffer writes in flight at once while deploying
+const WRITE_CONCURRENCY: usize = 16;
+
+// The program data account can't grow past its size at deploy time, so like `solana
+// program deploy` it reserves twice the program size for later upgrades
+const MAX_LEN_MULTIPLIER: usize = 2;
+
+pub async fn depository(
+    keypair_path: &str,
+    priority: Option<PriorityFee>,
+    action: DepositoryAction,
+    rpc_url: Option<&str>,
+    retry: RetryPolicy,
+) -> Result<()> {
     // Connect to the cluster
-    let rpc_url = dotenv::var("RPC_URL").expect("Missing solana rpc url");
+    let rpc_url = resolve_rpc_url(rpc_url)?;
     let client = RpcClient::new_with_commitment(rpc_url, CommitmentConfig::confirmed());
 
     // Load or create payer keypair
-    let payer = load_or_create_keypair()?;
+    let payer = load_or_create_keypair(keypair_path)?;
     println!("Using payer: {}", payer.pubkey());
 
     // Check and maybe fund payer account
     let balance = client.get_balance(&payer.pubkey()).await?;
-    println!("Payer balance: {} SOL", balance as f64 / 1_000_000_000.0);
+    println!("Payer balance: {} SOL", format_sol(balance));
 
     if balance < 1_000_000_000 {
         println!(
@@ -45,18 +93,64 @@ pub async fn depository() -> Result<()> {
         // Alternatively, you could automatically request an airdrop:
         // let sig = client.request_airdrop(&payer.pubkey(), 1_000_000_000)?;
         // client.confirm_transaction(&sig)?;
-        return Ok(());
+        // Scripts have to notice that nothing was done
+        if let DepositoryAction::Interactive = action {
+            return Ok(());
+        }
+        bail!("payer {} has insufficient funds", payer.pubkey());
     }
 
     // Load or deploy the program
-    let program_id = deploy_program_if_needed(&client, &payer).await?;
+    let program_id = deploy_program_if_needed(&client, &payer, retry).await?;
     println!("Using program ID: {program_id}");
 
     // Derive PDA for this user
     let (pda, _) = Pubkey::find_program_address(&[payer.pubkey().as_ref()], &program_id);
     println!("Derived PDA: {pda}");
 
-    // Menu for interacting with the program
+    let opts = SendOptions {
+        priority,
+        retry,
+        ..SendOptions::default()
+    };
+
+    match action {
+        DepositoryAction::Init => initialize_account(&client, &payer, &program_id, pda, opts).await,
+        DepositoryAction::Deposit { sol } => {
+            deposit_sol(
+                &client,
+                &payer,
+                &program_id,
+                pda,
+                sol_to_lamports(sol)?,
+                opts,
+            )
+            .await
+        }
+        DepositoryAction::Withdraw { sol } => {
+            withdraw_sol(
+                &client,
+                &payer,
+                &program_id,
+                pda,
+                sol_to_lamports(sol)?,
+                opts,
+            )
+            .await
+        }
+        DepositoryAction::Balance => check_balance(&client, &program_id, pda).await,
+        DepositoryAction::Interactive => interactive(&client, &payer, &program_id, pda, opts).await,
+    }
+}
+
+// Menu for interacting with the program
+async fn interactive(
+    client: &RpcClient,
+    payer: &Keypair,
+    program_id: &Pubkey,
+    pda: Pubkey,
+    opts: SendOptions,
+) -> Result<()> {
     loop {
         println!("\nDeposit Program Client");
         println!("1. Initialize account");
@@ -67,14 +161,26 @@ pub async fn depository() -> Result<()> {
         println!("Choose an option (1-5):");
 
         let mut choice = String::new();
-        io::stdin().read_line(&mut choice)?;
+        // Zero bytes read means stdin is closed, so no further choice can ever arrive
+        if io::stdin().read_line(&mut choice)? == 0 {
+            println!("Input closed, exiting");
+            break;
+        }
         let choice = choice.trim();
 
         match choice {
-            "1" => initialize_account(&client, &payer, &program_id, pda).await?,
-            "2" => deposit_sol(&client, &payer, &program_id, pda).await?,
-            "3" => withdraw_sol(&client, &payer, &program_id, pda).await?,
-            "4" => check_balance(&client, pda).await?,
+            "1" => initialize_account(client, payer, program_id, pda, opts).await?,
+            "2" => {
+                println!("Enter amount to deposit in SOL:");
+                let amount_lamports = read_lamports()?;
+                deposit_sol(client, payer, program_id, pda, amount_lamports, opts).await?
+            }
+            "3" => {
+                println!("Enter amount to withdraw in SOL:");
+                let amount_lamports = read_lamports()?;
+                withdraw_sol(client, payer, program_id, pda, amount_lamports, opts).await?
+            }
+            "4" => check_balance(client, program_id, pda).await?,
             "5" => break,
             _ => println!("Invalid choice, please try again"),
         }
@@ -83,15 +189,314 @@ pub async fn depository() -> Result<()> {
     Ok(())
 }
 
-fn load_or_create_keypair() -> Result<Keypair> {
-    let keypair_path = "dep_test_account.json";
+// Populates the program with `count` funded deposit accounts owned by fresh payers
+pub async fn seed_deposits(
+    program_id: String,
+    count: usize,
+    amount_sol: f64,
+    max_concurrency: usize,
+    min_success_rate: Option<f64>,
+    rpc_url: Option<&str>,
+    retry: RetryPolicy,
+) -> Result<()> {
+    if max_concurrency == 0 {
+        bail!("max concurrency must be at least 1");
+    }
+    let program_id = Pubkey::from_str(&program_id)?;
+    let amount_lamports = sol_to_lamports(amount_sol)?;
+
+    // Connect to the cluster
+    let rpc_url = resolve_rpc_url(rpc_url)?;
+    let client = RpcClient::new_with_commitment(rpc_url, CommitmentConfig::confirmed());
+
+    println!(
+        "Seeding {count} deposit accounts with {} SOL each...",
+        format_sol(amount_lamports)
+    );
+    let results: Vec<_> = stream::iter(0..count)
+        .map(|_| seed_deposit(&client, &program_id, amount_lamports, retry))
+        .buffer_unordered(max_concurrency)
+        .collect()
+        .await;
+
+    let mut failures = Vec::new();
+    for result in results {
+        match result {
+            Ok(pda) => println!("Seeded deposit account {pda}"),
+            Err(err) => failures.push(err),
+        }
+    }
+
+    println!("\nSummary:");
+    println!("Successful: {}", count - failures.len());
+    println!("Failed: {}", failures.len());
+    for err in &failures {
+        println!("  {err}");
+    }
+
+    check_success_rate(count - failures.len(), count, min_success_rate)
+}
+
+async fn seed_deposit(
+    client: &RpcClient,
+    program_id: &Pubkey,
+    amount_lamports: u64,
+    retry: RetryPolicy,
+) -> Result<Pubkey> {
+    let payer = Keypair::new();
+
+    // Fund the new payer from the faucet, leaving headroom for fees
+    let airdrop = client
+        .request_airdrop(&payer.pubkey(), amount_lamports + LAMPORTS_PER_SOL / 10)
+        .await?;
+    client.poll_for_signature(&airdrop).await?;
 
-    if Path::new(&keypair_path).exists() {
+    open_deposit(client, program_id, &payer, amount_lamports, retry).await
+}
+
+// Initializes the payer's PDA and deposits into it in one transaction
+pub async fn open_deposit(
+    client: &RpcClient,
+    program_id: &Pubkey,
+    payer: &Keypair,
+    amount_lamports: u64,
+    retry: RetryPolicy,
+) -> Result<Pubkey> {
+    let (pda, _) = Pubkey::find_program_address(&[payer.pubkey().as_ref()], program_id);
+    send_resilient(
+        client,
+        &[
+            initialize_ix(program_id, &payer.pubkey(), &pda),
+            deposit_ix(program_id, &payer.pubkey(), &pda, amount_lamports),
+        ],
+        &[payer],
+        &payer.pubkey(),
+        SendOptions {
+            retry,
+            ..SendOptions::default()
+        },
+    )
+    .await?
+    .confirmed()?;
+
+    Ok(pda)
+}
+
+// Hands a deposit account over to `new_owner`, signed by its current owner
+pub async fn change_owner(
+    program_id: String,
+    keypair_path: &str,
+    account: Option<String>,
+    new_owner: String,
+    rpc_url: Option<&str>,
+    retry: RetryPolicy,
+) -> Result<()> {
+    let program_id = Pubkey::from_str(&program_id)?;
+    let new_owner = Pubkey::from_str(&new_owner)?;
+    let owner =
+        load_keypair(keypair_path).map_err(|e| anyhow!("failed to read owner keypair: {e}"))?;
+
+    // Accounts that already changed hands no longer derive from their owner's key
+    let pda = match account {
+        Some(account) => Pubkey::from_str(&account)?,
+        None => Pubkey::find_program_address(&[owner.pubkey().as_ref()], &program_id).0,
+    };
+
+    // Connect to the cluster
+    let rpc_url = resolve_rpc_url(rpc_url)?;
+    let client = RpcClient::new_with_commitment(rpc_url, CommitmentConfig::confirmed());
+
+    println!(
+        "Changing owner of {pda} from {} to {new_owner}...",
+        owner.pubkey()
+    );
+    let signature = send_resilient(
+        &client,
+        &[change_owner_ix(
+            &program_id,
+            &owner.pubkey(),
+            &pda,
+            &new_owner,
+        )],
+        &[&owner],
+        &owner.pubkey(),
+        SendOptions {
+            retry,
+            ..SendOptions::default()
+        },
+    )
+    .await?
+    .confirmed()?;
+    println!("Owner changed! Transaction signature: {signature}");
+
+    Ok(())
+}
+
+// Balance recorded in the deposit account's data, as opposed to its lamports
+pub async fn stored_balance(client: &RpcClient, pda: &Pubkey) -> Result<u64> {
+    let data = client.get_account_data(pda).await?;
+    let Ok(deposit) = DepositAccount::deserialize(&mut data.as_slice()) else {
+        bail!("account {pda} is not a deposit account");
+    };
+    Ok(deposit.balance)
+}
+
+#[derive(Debug)]
+struct DepositEntry {
+    pda: Pubkey,
+    account: DepositAccount,
+    lamports: u64,
+}
+
+// Lists every deposit account of the program, optionally refreshing on an interval
+pub async fn deposit_list(
+    program_id: String,
+    data_size: u64,
+    watch: Option<u64>,
+    rpc_url: Option<&str>,
+) -> Result<()> {
+    let program_id = Pubkey::from_str(&program_id)?;
+
+    // Connect to the cluster
+    let rpc_url = resolve_rpc_url(rpc_url)?;
+    let client = RpcClient::new_with_commitment(rpc_url, CommitmentConfig::confirmed());
+
+    let Some(interval) = watch else {
+        print_deposits(&scan_deposits(&client, &program_id, data_size).await?);
+        return Ok(());
+    };
+
+    loop {
+        let deposits = scan_deposits(&client, &program_id, data_size).await?;
+        // Clear the screen and redraw the table from the top
+        print!("\x1B[2J\x1B[1;1H");
+        print_deposits(&deposits);
+        println!("\nRefreshing every {interval}s, press Ctrl-C to exit");
+
+        tokio::select! {
+            _ = signal::ctrl_c() => break,
+            _ = sleep(Duration::from_secs(interval)) => {}
+        }
+    }
+
+    Ok(())
+}
+
+// Only accounts of exactly `data_size` bytes are fetched, which keeps other
+// program-owned accounts such as merkle trees out of the scan.
+async fn scan_deposits(
+    client: &RpcClient,
+    program_id: &Pubkey,
+    data_size: u64,
+) -> Result<Vec<DepositEntry>> {
+    let config = RpcProgramAccountsConfig {
+        filters: Some(vec![RpcFilterType::DataSize(data_size)]),
+        ..Default::default()
+    };
+    let accounts = client
+        .get_program_accounts_with_config(program_id, config)
+        .await?;
+
+    let mut deposits: Vec<_> = accounts
+        .into_iter()
+        .filter_map(|(pda, account)| {
+            // Skip program-owned accounts that don't decode as deposits
+            let deposit = DepositAccount::deserialize(&mut account.data.as_slice()).ok()?;
+            Some(DepositEntry {
+                pda,
+                account: deposit,
+                lamports: account.lamports,
+            })
+        })
+        .collect();
+    deposits.sort_by_key(|deposit| std::cmp::Reverse(deposit.account.balance));
+    Ok(deposits)
+}
+
+#[derive(Debug, Serialize)]
+struct DepositSnapshot {
+    program_id: String,
+    slot: u64,
+    // Sum of the balances stored in every deposit account
+    total_value_locked: u64,
+    total_lamports: u64,
+    accounts: Vec<SnapshotEntry>,
+}
+
+#[derive(Debug, Serialize)]
+struct SnapshotEntry {
+    pubkey: String,
+    balance: u64,
+    lamports: u64,
+}
+
+// Writes every deposit account of the program to a single JSON file
+pub async fn deposit_snapshot(
+    program_id: String,
+    data_size: u64,
+    out: String,
+    rpc_url: Option<&str>,
+) -> Result<()> {
+    let program_id = Pubkey::from_str(&program_id)?;
+
+    // Connect to the cluster
+    let rpc_url = resolve_rpc_url(rpc_url)?;
+    let client = RpcClient::new_with_commitment(rpc_url, CommitmentConfig::confirmed());
+
+    // The scan itself doesn't report a slot, so record the one it started at
+    let slot = client.get_slot().await?;
+    let deposits = scan_deposits(&client, &program_id, data_size).await?;
+
+    let snapshot = DepositSnapshot {
+        program_id: program_id.to_string(),
+        slot,
+        total_value_locked: deposits.iter().map(|deposit| deposit.account.balance).sum(),
+        total_lamports: deposits.iter().map(|deposit| deposit.lamports).sum(),
+        accounts: deposits
+            .iter()
+            .map(|deposit| SnapshotEntry {
+                pubkey: deposit.pda.to_string(),
+                balance: deposit.account.balance,
+                lamports: deposit.lamports,
+            })
+            .collect(),
+    };
+    fs::write(&out, serde_json::to_string_pretty(&snapshot)?)?;
+    println!(
+        "Saved {} deposit accounts at slot {slot} to {out}",
+        snapshot.accounts.len()
+    );
+
+    Ok(())
+}
+
+fn print_deposits(deposits: &[DepositEntry]) {
+    println!(
+        "{:<44} {:>20} {:>20}",
+        "Account", "Stored (SOL)", "Lamports"
+    );
+    println!("{}", "-".repeat(86));
+    for deposit in deposits {
+        println!(
+            "{:<44} {:>20} {:>20}",
+            deposit.pda,
+            format_sol(deposit.account.balance),
+            deposit.lamports
+        );
+    }
+
+    let total: u64 = deposits.iter().map(|deposit| deposit.account.balance).sum();
+    println!("\nAccounts: {}", deposits.len());
+    println!("Total deposits: {} SOL", format_sol(total));
+}
+
+fn load_or_create_keypair(keypair_path: &str) -> Result<Keypair> {
+    if Path::new(keypair_path).exists() {
         println!("Loading keypair from {keypair_path}");
         let keypair_bytes = fs::read(keypair_path)?;
         let keypair_str = String::from_utf8(keypair_bytes)?;
         let keypair_vec: Vec<u8> = serde_json::from_str(&keypair_str)?;
-        return Ok(Keypair::from_bytes(&keypair_vec)?);
+        return Ok(Keypair::try_from(keypair_vec.as_slice())?);
     }
 
     // Create a new keypair
@@ -107,7 +512,12 @@ fn load_or_create_keypair() -> Result<Keypair> {
     Ok(keypair)
 }
 
-async fn deploy_program_if_needed(client: &RpcClient, payer: &Keypair) -> Result<Pubkey> {
+#[allow(deprecated)]
+async fn deploy_program_if_needed(
+    client: &RpcClient,
+    payer: &Keypair,
+    retry: RetryPolicy,
+) -> Result<Pubkey> {
     // Check if we have a saved program id
     let home_dir = env::var("HOME").unwrap_or_else(|_| ".".to_string());
     let program_id_path = format!("{home_dir}/.config/solana/deposit_program_id.txt");
@@ -139,49 +549,88 @@ async fn deploy_program_if_needed(client: &RpcClient, payer: &Keypair) -> Result
 
     // Read the program ELF
     let program_data = fs::read(program_path)?;
+    let program_len = program_data.len();
 
     // Create a new keypair for the program
     let program_keypair = Keypair::new();
     let program_id = program_keypair.pubkey();
 
-    // Calculate required space
-    let program_len = program_data.len();
+    let opts = SendOptions {
+        retry,
+        ..SendOptions::default()
+    };
 
-    // Calculate minimum balance required for the program account
-    let lamports = client
-        .get_minimum_balance_for_rent_exemption(program_len)
+    // The ELF is staged in a buffer account, with the payer as its authority
+    let buffer = Keypair::new();
+    let buffer_lamports = client
+        .get_minimum_balance_for_rent_exemption(UpgradeableLoaderState::size_of_buffer(program_len))
         .await?;
-
-    // Create the program account
-    let create_account_instr = system_instruction::create_account(
+    let create_buffer = bpf_loader_upgradeable::create_buffer(
+        &payer.pubkey(),
+        &buffer.pubkey(),
+        &payer.pubkey(),
+        buffer_lamports,
+        program_len,
+    )?;
+    send_resilient(
+        client,
+        &create_buffer,
+        &[payer, &buffer],
+        &payer.pubkey(),
+        opts,
+    )
+    .await?
+    .confirmed()?;
+    println!("Created buffer account {}", buffer.pubkey());
+
+    // Every chunk goes to its own offset, so the writes don't depend on each other
+    let chunks: Vec<_> = program_data.chunks(WRITE_CHUNK_SIZE).enumerate().collect();
+    let chunk_count = chunks.len();
+    let results = bounded_map(chunks, WRITE_CONCURRENCY, |(i, chunk)| {
+        let write = bpf_loader_upgradeable::write(
+            &buffer.pubkey(),
+            &payer.pubkey(),
+            (i * WRITE_CHUNK_SIZE) as u32,
+            chunk.to_vec(),
+        );
+        async move {
+            send_resilient(client, &[write], &[payer], &payer.pubkey(), opts)
+                .await?
+                .confirmed()
+        }
+    })
+    .await?;
+    results.into_iter().collect::<Result<Vec<_>>>()?;
+    println!("Wrote {program_len} bytes in {chunk_count} transactions");
+
+    // Creates the program account and moves the buffer into its program data account
+    let program_lamports = client
+        .get_minimum_balance_for_rent_exemption(UpgradeableLoaderState::size_of_program())
+        .await?;
+    let deploy = bpf_loader_upgradeable::deploy_with_max_program_len(
         &payer.pubkey(),
         &program_id,
-        lamports,
-        program_len as u64,
-        &bpf_loader_upgradeable::id(),
-    );
-
-    let transaction = Transaction::new_signed_with_payer(
-        &[create_account_instr],
-        Some(&payer.pubkey()),
+        &buffer.pubkey(),
+        &payer.pubkey(),
+        program_lamports,
+        program_len * MAX_LEN_MULTIPLIER,
+    )?;
+    let signature = send_resilient(
+        client,
+        &deploy,
         &[payer, &program_keypair],
-        client.get_latest_blockhash().await?,
-    );
-
-    client.send_and_confirm_transaction(&transaction).await?;
-    println!("Created program account");
-
-    // Write program data to the account
-    // Note: In a real deployment, you would use BPF loader to load the program
-    // This is a simplified example - in practice, you'd use the solana CLI
-    println!("For a real deployment, use the Solana CLI:");
-    println!("solana program deploy {program_path}");
+        &payer.pubkey(),
+        opts,
+    )
+    .await?
+    .confirmed()?;
+    println!("Program deployed at {program_id}: {signature}");
 
     // Save the program ID for future use
     fs::write(&program_id_path, program_id.to_string())?;
     println!("Program ID saved to {program_id_path}");
 
-    bail!("Deploy program and restart")
+    Ok(program_id)
 }
 
 async fn initialize_account(
@@ -189,79 +638,50 @@ async fn initialize_account(
     payer: &Keypair,
     program_id: &Pubkey,
     pda: Pubkey,
+    opts: SendOptions,
 ) -> Result<()> {
     println!("Initializing account...");
 
-    // Create instruction data for Initialize
-    let instruction_data = DepositInstruction::Initialize;
-
     // Create the instruction
-    let instruction = Instruction::new_with_borsh(
-        *program_id,
-        &instruction_data,
-        vec![
-            AccountMeta::new(payer.pubkey(), true),
-            AccountMeta::new(pda, false),
-            AccountMeta::new_readonly(solana_program::system_program::id(), false),
-        ],
-    );
+    let instruction = initialize_ix(program_id, &payer.pubkey(), &pda);
 
     // Create and send the transaction
-    let transaction = Transaction::new_signed_with_payer(
-        &[instruction],
-        Some(&payer.pubkey()),
-        &[payer],
-        client.get_latest_blockhash().await?,
-    );
-
-    let signature = client.send_and_confirm_transaction(&transaction).await?;
+    let signature = send_resilient(client, &[instruction], &[payer], &payer.pubkey(), opts)
+        .await?
+        .confirmed()?;
     println!("Account initialized! Transaction signature: {signature}");
     Ok(())
 }
 
+// Reads an amount in SOL from stdin
+fn read_lamports() -> Result<u64> {
+    let mut amount_str = String::new();
+    io::stdin().read_line(&mut amount_str)?;
+    let amount_sol = amount_str.trim().parse::<f64>()?;
+    sol_to_lamports(amount_sol)
+}
+
 async fn deposit_sol(
     client: &RpcClient,
     payer: &Keypair,
     program_id: &Pubkey,
     pda: Pubkey,
+    amount_lamports: u64,
+    opts: SendOptions,
 ) -> Result<()> {
-    println!("Enter amount to deposit in SOL:");
-    let mut amount_str = String::new();
-    io::stdin().read_line(&mut amount_str)?;
-    let amount_sol = amount_str.trim().parse::<f64>()?;
-    let amount_lamports = (amount_sol * 1_000_000_000.0) as u64;
-
     println!(
         "Depositing {} SOL ({} lamports)...",
-        amount_sol, amount_lamports
+        format_sol(amount_lamports),
+        amount_lamports
     );
 
-    // Create instruction data for Deposit
-    let instruction_data = DepositInstruction::Deposit;
-
-    // First transfer SOL to the program account
-    let transfer_instruction = system_instruction::transfer(&payer.pubkey(), &pda, amount_lamports);
-
-    // Then update the balance in the account's data
-    let deposit_instruction = Instruction::new_with_borsh(
-        *program_id,
-        &instruction_data,
-        vec![
-            AccountMeta::new(payer.pubkey(), true),
-            AccountMeta::new(pda, false),
-            AccountMeta::new_readonly(solana_program::system_program::id(), false),
-        ],
-    );
+    // The program moves the lamports and records them in the account's data
+    let instruction = deposit_ix(program_id, &payer.pubkey(), &pda, amount_lamports);
 
     // Create and send the transaction
-    let transaction = Transaction::new_signed_with_payer(
-        &[transfer_instruction, deposit_instruction],
-        Some(&payer.pubkey()),
-        &[payer],
-        client.get_latest_blockhash().await?,
-    );
-
-    let signature = client.send_and_confirm_transaction(&transaction).await?;
+    let signature = send_resilient(client, &[instruction], &[payer], &payer.pubkey(), opts)
+        .await?
+        .confirmed()?;
     println!("Deposit successful! Transaction signature: {}", signature);
     Ok(())
 }
@@ -271,42 +691,22 @@ async fn withdraw_sol(
     payer: &Keypair,
     program_id: &Pubkey,
     pda: Pubkey,
+    amount_lamports: u64,
+    opts: SendOptions,
 ) -> Result<()> {
-    println!("Enter amount to withdraw in SOL:");
-    let mut amount_str = String::new();
-    io::stdin().read_line(&mut amount_str)?;
-    let amount_sol = amount_str.trim().parse::<f64>()?;
-    let amount_lamports = (amount_sol * 1_000_000_000.0) as u64;
-
     println!(
         "Withdrawing {} SOL ({} lamports)...",
-        amount_sol, amount_lamports
+        format_sol(amount_lamports),
+        amount_lamports
     );
 
-    // Create instruction data for Withdraw
-    let mut instruction_data = vec![2]; // Instruction index for Withdraw
-    instruction_data.extend_from_slice(&amount_lamports.to_le_bytes());
-
     // Create the instruction
-    let instruction = Instruction::new_with_bytes(
-        *program_id,
-        &instruction_data,
-        vec![
-            AccountMeta::new(payer.pubkey(), true),
-            AccountMeta::new(pda, false),
-            AccountMeta::new_readonly(solana_program::system_program::id(), false),
-        ],
-    );
+    let instruction = withdraw_ix(program_id, &payer.pubkey(), &pda, amount_lamports);
 
     // Create and send the transaction
-    let transaction = Transaction::new_signed_with_payer(
-        &[instruction],
-        Some(&payer.pubkey()),
-        &[payer],
-        client.get_latest_blockhash().await?,
-    );
-
-    let signature = client.send_and_confirm_transaction(&transaction).await?;
+    let signature = send_resilient(client, &[instruction], &[payer], &payer.pubkey(), opts)
+        .await?
+        .confirmed()?;
     println!(
         "Withdrawal successful! Transaction signature: {}",
         signature
@@ -314,29 +714,35 @@ async fn withdraw_sol(
     Ok(())
 }
 
-async fn check_balance(client: &RpcClient, pda: Pubkey) -> Result<()> {
+async fn check_balance(client: &RpcClient, program_id: &Pubkey, pda: Pubkey) -> Result<()> {
     println!("Checking account balance...");
 
     // Get account info - lamports and data
     match client.get_account(&pda).await {
+        Ok(account) if account.owner != *program_id => {
+            println!(
+                "Account {pda} is not a deposit account owned by this program (owner: {}).",
+                account.owner
+            );
+        }
         Ok(account) => {
             let lamports_balance = account.lamports;
             println!(
                 "Account lamports: {} ({} SOL)",
                 lamports_balance,
-                lamports_balance as f64 / 1_000_000_000.0
+                format_sol(lamports_balance)
             );
 
             // Try to read the stored balance from account data
-            if account.data.len() >= 8 {
-                let stored_balance = u64::from_le_bytes(account.data[0..8].try_into().unwrap());
-                println!(
+            match DepositAccount::deserialize(&mut account.data.as_slice()) {
+                Ok(deposit) => println!(
                     "Stored balance: {} ({} SOL)",
-                    stored_balance,
-                    stored_balance as f64 / 1_000_000_000.0
-                );
-            } else {
-                println!("Account doesn't have valid data yet. Please initialize it first.");
+                    deposit.balance,
+                    format_sol(deposit.balance)
+                ),
+                Err(_) => {
+                    println!("Account doesn't have valid data yet. Please initialize it first.")
+                }
             }
         }
         Err(_) => {
@@ -1,65 +1,434 @@
-use std::str::FromStr;
+use std::{
+    collections::{HashMap, HashSet},
+    str::FromStr,
+};
 
-use anyhow::{Result, anyhow};
-use futures::{StreamExt, stream::FuturesUnordered};
+use anyhow::{Result, anyhow, bail};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use clap::ValueEnum;
 use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
 use solana_client::nonblocking::rpc_client::RpcClient;
-use solana_sdk::{native_token::LAMPORTS_PER_SOL, pubkey::Pubkey};
-use tokio::fs;
+use solana_sdk::{commitment_config::CommitmentConfig, pubkey::Pubkey};
+use tokio::{fs, io::AsyncReadExt};
+
+use crate::{
+    config::load_config_with,
+    recipients::SeededRecipients,
+    retry::{RetryPolicy, is_transient, is_transient_http},
+    util::{bounded_map, format_sol, resolve_rpc_url},
+};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Balance {
     pub pubkey: Pubkey,
     pub balance: u64,
+    // Base64-encoded raw account data, only present in raw mode
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<String>,
 }
 
-pub async fn get_balances(file: String) -> Result<()> {
-    // Read config from YAML file
-    let mut wallets: Vec<String> = serde_yaml::from_str(&fs::read_to_string(file).await?)?;
+// Wallet skipped because its address doesn't parse as a pubkey
+#[derive(Debug, Serialize)]
+struct InvalidAddress {
+    address: String,
+    error: String,
+}
 
-    // Connect to Solana network
-    let rpc_url = dotenv::var("RPC_URL").expect("Missing solana rpc url");
-    let rpc_client = RpcClient::new(rpc_url);
-
-    // Retrieve and display balance for each wallet
-    let mut handlers = FuturesUnordered::new();
-    let handlers_limit = 50;
-
-    let get_single_balance = async |wallet_address: String| {
-        let pubkey = Pubkey::from_str(&wallet_address)?;
-        let balance = rpc_client
-            .get_balance(&pubkey)
-            .await
-            .map_err(|e| anyhow!("failed to get balances: {e}"))?;
-        Result::<_, anyhow::Error>::Ok(Balance { pubkey, balance })
+// How balances are requested from the RPC node
+#[derive(Clone, Copy, Debug, Default, ValueEnum)]
+pub enum BalanceMode {
+    // One `getMultipleAccounts` request per chunk of wallets
+    #[default]
+    MultipleAccounts,
+    // One request per wallet
+    Single,
+    // JSON-RPC 2.0 batches of `getBalance` calls, for providers without `getMultipleAccounts`
+    BatchJsonrpc,
+}
+
+// Serialization format of the balances file
+#[derive(Clone, Copy, Debug, Default, ValueEnum)]
+pub enum OutputFormat {
+    #[default]
+    Yaml,
+    Json,
+}
+
+impl OutputFormat {
+    // File the balances are written to when no output path is given
+    fn default_path(self) -> &'static str {
+        match self {
+            Self::Yaml => "balances.yaml",
+            Self::Json => "balances.json",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct BalanceOptions {
+    // Include base64-encoded raw account data in the output
+    pub raw: bool,
+    pub mode: BalanceMode,
+    // Maximum number of balance requests in flight at once
+    pub max_concurrency: usize,
+    pub format: OutputFormat,
+    // Leave wallets holding less than this out of the output
+    pub min_lamports: u64,
+    // Reject unknown fields in the config instead of ignoring them
+    pub strict_config: bool,
+    pub retry: RetryPolicy,
+}
+
+// Number of `getBalance` calls packed into a single HTTP request
+const JSONRPC_BATCH_SIZE: usize = 100;
+
+// Maximum number of accounts `getMultipleAccounts` accepts
+const MULTIPLE_ACCOUNTS_BATCH_SIZE: usize = 100;
+
+pub async fn get_balances(
+    file: String,
+    recipients: Option<SeededRecipients>,
+    output: Option<String>,
+    invalid_output: Option<String>,
+    rpc_url: Option<&str>,
+    options: BalanceOptions,
+) -> Result<()> {
+    let BalanceOptions {
+        raw,
+        mode,
+        max_concurrency,
+        format,
+        min_lamports,
+        strict_config,
+        retry,
+    } = options;
+    if max_concurrency == 0 {
+        bail!("--max-concurrency must be at least 1");
+    }
+    if raw && matches!(mode, BalanceMode::BatchJsonrpc) {
+        bail!("raw mode isn't supported together with batch-jsonrpc");
+    }
+
+    // Read wallets config, unless checking seeded load test recipients
+    let wallets: Vec<String> = match recipients {
+        Some(recipients) => recipients.pubkeys().iter().map(Pubkey::to_string).collect(),
+        // Whitespace-separated addresses piped in on stdin
+        None if file == "-" => {
+            let mut input = String::new();
+            tokio::io::stdin().read_to_string(&mut input).await?;
+            input.split_whitespace().map(str::to_string).collect()
+        }
+        None => load_config_with(&file, strict_config).await?,
     };
 
-    let first_wallet = wallets.pop().expect("empty wallets");
+    // Skip malformed addresses up front instead of spending requests on them
+    let (wallets, invalid) = partition_addresses(wallets);
 
-    handlers.push(get_single_balance(first_wallet));
+    // Configs built by concatenation may list a wallet more than once, keep the first one
+    let mut seen = HashSet::new();
+    let wallets_count = wallets.len();
+    let wallets: Vec<Pubkey> = wallets
+        .into_iter()
+        .filter(|wallet| seen.insert(*wallet))
+        .collect();
+    let duplicates = wallets_count - wallets.len();
+    if duplicates > 0 {
+        println!("Removed {duplicates} duplicate wallets");
+    }
 
-    let mut balances = Vec::new();
-    while !wallets.is_empty() {
-        if handlers.len() >= handlers_limit {
-            let blnc = handlers.next().await.unwrap()?;
-            balances.push(blnc);
-            continue;
+    // Connect to Solana network
+    let rpc_url = resolve_rpc_url(rpc_url)?;
+    let rpc_client = RpcClient::new(rpc_url.clone());
+
+    // Keep the wallet address next to its result so failures can be reported
+    let get_single_balance = async |pubkey: Pubkey| {
+        let balance = async {
+            let (balance, data) = if raw {
+                // Fetch the whole account so consumers can decode the data themselves
+                let account = retry
+                    .run(
+                        || rpc_client.get_account_with_commitment(&pubkey, rpc_client.commitment()),
+                        is_transient,
+                    )
+                    .await
+                    .map_err(|e| anyhow!("failed to get account: {e}"))?
+                    .value;
+                match account {
+                    Some(account) => (account.lamports, Some(STANDARD.encode(account.data))),
+                    None => (0, None),
+                }
+            } else {
+                let balance = retry
+                    .run(|| rpc_client.get_balance(&pubkey), is_transient)
+                    .await
+                    .map_err(|e| anyhow!("failed to get balances: {e}"))?;
+                (balance, None)
+            };
+            Result::<_, anyhow::Error>::Ok(Balance {
+                pubkey,
+                balance,
+                data,
+            })
         }
-        let next = wallets.pop().expect("unreachable");
+        .await;
+        (pubkey, balance)
+    };
 
-        handlers.push(get_single_balance(next));
+    let mut balances = Vec::new();
+    let mut failures = Vec::new();
+    let results = match mode {
+        BalanceMode::MultipleAccounts => {
+            let batches = wallets
+                .chunks(MULTIPLE_ACCOUNTS_BATCH_SIZE)
+                .map(<[Pubkey]>::to_vec);
+            bounded_map(batches, max_concurrency, |batch| {
+                get_multiple_balances(&rpc_client, raw, retry, batch)
+            })
+            .await?
+            .into_iter()
+            .flatten()
+            .collect()
+        }
+        BalanceMode::Single => {
+            bounded_map(wallets, max_concurrency, |wallet| {
+                get_single_balance(wallet)
+            })
+            .await?
+        }
+        BalanceMode::BatchJsonrpc => {
+            let http_client = reqwest::Client::new();
+            let commitment = rpc_client.commitment();
+            let batches = wallets.chunks(JSONRPC_BATCH_SIZE).map(<[Pubkey]>::to_vec);
+            bounded_map(batches, max_concurrency, |batch| {
+                get_batch_balances(&http_client, &rpc_url, commitment, retry, batch)
+            })
+            .await?
+            .into_iter()
+            .flatten()
+            .collect()
+        }
+    };
+    for (wallet, result) in results {
+        match result {
+            Ok(blnc) => balances.push(blnc),
+            Err(e) => failures.push((wallet, e)),
+        }
     }
 
+    let fetched = balances.len();
+    balances.retain(|balance| balance.balance >= min_lamports);
+    let filtered_out = fetched - balances.len();
+
     for b in &balances {
-        println!(
-            "{} - {} SOL",
-            b.pubkey,
-            b.balance as f64 / LAMPORTS_PER_SOL as f64
-        );
+        println!("{} - {} SOL", b.pubkey, format_sol(b.balance));
     }
 
-    let output = serde_yaml::to_string(&balances)?;
-    fs::write("balances.yaml", output).await?;
+    let content = match format {
+        OutputFormat::Yaml => serde_yaml::to_string(&balances)?,
+        OutputFormat::Json => serde_json::to_string_pretty(&balances)?,
+    };
+    let output = output.as_deref().unwrap_or(format.default_path());
+    fs::write(output, content).await?;
+
+    print_summary(&balances);
+
+    if filtered_out > 0 {
+        println!("\nFiltered out {filtered_out} wallets below threshold");
+    }
+
+    // Report wallets that couldn't be fetched instead of aborting the whole run
+    if !failures.is_empty() {
+        println!("\nFailed to get balances for {} wallets:", failures.len());
+        for (wallet, err) in &failures {
+            println!("{wallet} - {err}");
+        }
+    }
+
+    if !invalid.is_empty() {
+        println!("\nSkipped {} invalid addresses:", invalid.len());
+        for InvalidAddress { address, error } in &invalid {
+            println!("{address} - {error}");
+        }
+    }
+    // Written even when empty, so a stale list from a previous run can't be mistaken for this one
+    if let Some(path) = invalid_output {
+        fs::write(&path, serde_yaml::to_string(&invalid)?).await?;
+    }
 
     Ok(())
 }
+
+// Splits addresses into the ones that parse as pubkeys and the ones that don't, keeping
+// the input order of both
+fn partition_addresses(addresses: Vec<String>) -> (Vec<Pubkey>, Vec<InvalidAddress>) {
+    let mut valid = Vec::new();
+    let mut invalid = Vec::new();
+    for address in addresses {
+        match Pubkey::from_str(&address) {
+            Ok(pubkey) => valid.push(pubkey),
+            Err(e) => invalid.push(InvalidAddress {
+                address,
+                error: e.to_string(),
+            }),
+        }
+    }
+    (valid, invalid)
+}
+
+// Aggregate view of the written balances
+fn print_summary(balances: &[Balance]) {
+    let (Some(min), Some(max)) = (
+        balances.iter().map(|b| b.balance).min(),
+        balances.iter().map(|b| b.balance).max(),
+    ) else {
+        return;
+    };
+    let total: u64 = balances.iter().map(|b| b.balance).sum();
+    let mean = total / balances.len() as u64;
+    let empty = balances.iter().filter(|b| b.balance == 0).count();
+
+    println!("\nSummary:");
+    println!("Total wallets: {}", balances.len());
+    println!("Total balance: {} SOL", format_sol(total));
+    println!("Mean balance: {} SOL", format_sol(mean));
+    println!("Min balance: {} SOL", format_sol(min));
+    println!("Max balance: {} SOL", format_sol(max));
+    println!("Empty wallets: {empty}");
+}
+
+// Fetches a chunk of wallets with a single `getMultipleAccounts` call, accounts that
+// don't exist have a zero balance
+async fn get_multiple_balances(
+    rpc_client: &RpcClient,
+    raw: bool,
+    retry: RetryPolicy,
+    wallets: Vec<Pubkey>,
+) -> Vec<(Pubkey, Result<Balance>)> {
+    let accounts = match retry
+        .run(|| rpc_client.get_multiple_accounts(&wallets), is_transient)
+        .await
+    {
+        Ok(accounts) => accounts,
+        // The whole chunk failed, so every wallet in it did
+        Err(e) => {
+            return wallets
+                .into_iter()
+                .map(|wallet| (wallet, Err(anyhow!("failed to get balances: {e}"))))
+                .collect();
+        }
+    };
+
+    wallets
+        .into_iter()
+        .zip(accounts)
+        .map(|(pubkey, account)| {
+            let (balance, data) = match account {
+                // Keep the whole account data around so consumers can decode it themselves
+                Some(account) => (account.lamports, raw.then(|| STANDARD.encode(account.data))),
+                None => (0, None),
+            };
+            let balance = Balance {
+                pubkey,
+                balance,
+                data,
+            };
+            (pubkey, Ok(balance))
+        })
+        .collect()
+}
+
+// Sends one JSON-RPC batch request with a `getBalance` call per wallet
+async fn get_batch_balances(
+    http_client: &reqwest::Client,
+    rpc_url: &str,
+    commitment: CommitmentConfig,
+    retry: RetryPolicy,
+    wallets: Vec<Pubkey>,
+) -> Vec<(Pubkey, Result<Balance>)> {
+    let requests: Vec<_> = wallets
+        .iter()
+        .enumerate()
+        .map(|(id, wallet)| {
+            json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "method": "getBalance",
+                "params": [wallet.to_string(), commitment],
+            })
+        })
+        .collect();
+
+    let responses = retry
+        .run(
+            || async {
+                http_client
+                    .post(rpc_url)
+                    .json(&requests)
+                    .send()
+                    .await?
+                    .error_for_status()?
+                    .json::<Vec<Value>>()
+                    .await
+            },
+            is_transient_http,
+        )
+        .await;
+    let responses = match responses {
+        Ok(responses) => responses,
+        // The whole batch failed, so every wallet in it did
+        Err(e) => {
+            return wallets
+                .into_iter()
+                .map(|wallet| (wallet, Err(anyhow!("failed to get balances: {e}"))))
+                .collect();
+        }
+    };
+
+    // Responses may come back in any order, so match them to wallets by id
+    let mut by_id: HashMap<u64, Value> = responses
+        .into_iter()
+        .filter_map(|response| Some((response["id"].as_u64()?, response)))
+        .collect();
+    wallets
+        .into_iter()
+        .enumerate()
+        .map(|(id, wallet)| {
+            let balance = match by_id.remove(&(id as u64)) {
+                None => Err(anyhow!("no response in batch")),
+                Some(response) => match response["result"]["value"].as_u64() {
+                    Some(balance) => Ok(Balance {
+                        pubkey: wallet,
+                        balance,
+                        data: None,
+                    }),
+                    None => Err(anyhow!("failed to get balances: {}", response["error"])),
+                },
+            };
+            (wallet, balance)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn partition_addresses_splits_valid_and_garbage() {
+        let first = Pubkey::new_unique();
+        let second = Pubkey::new_unique();
+        let addresses = vec![
+            first.to_string(),
+            "not-a-pubkey".to_string(),
+            second.to_string(),
+            String::new(),
+        ];
+
+        let (valid, invalid) = partition_addresses(addresses);
+
+        assert_eq!(valid, vec![first, second]);
+        let invalid: Vec<&str> = invalid.iter().map(|i| i.address.as_str()).collect();
+        assert_eq!(invalid, vec!["not-a-pubkey", ""]);
+    }
+}
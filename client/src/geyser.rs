@@ -1,39 +1,138 @@
-use anyhow::{Result, anyhow};
+use anyhow::{Result, anyhow, bail};
 use futures::StreamExt;
 use serde::{Deserialize, Serialize};
-use solana_client::nonblocking::rpc_client::RpcClient;
+use solana_client::{client_error::ClientError, nonblocking::rpc_client::RpcClient};
+#[allow(deprecated)]
+use solana_sdk::system_instruction::{self, SystemError};
 use solana_sdk::{
     commitment_config::CommitmentConfig,
-    message::Message,
+    instruction::InstructionError,
     native_token::LAMPORTS_PER_SOL,
     pubkey::Pubkey,
     signature::{Keypair, Signer},
-    signer::EncodableKey,
-    system_instruction,
-    transaction::Transaction,
+    transaction::TransactionError,
 };
-use std::{collections::HashMap, str::FromStr, time::Duration};
-use tokio::{fs, time::sleep};
+use std::{
+    collections::{HashMap, VecDeque},
+    str::FromStr,
+    time::{Duration, Instant},
+};
+use tokio::time::sleep;
 use tonic::transport::ClientTlsConfig;
 use yellowstone_grpc_client::GeyserGrpcClient;
-use yellowstone_grpc_proto::geyser::{SubscribeRequest, SubscribeRequestFilterBlocks};
+use yellowstone_grpc_proto::geyser::{
+    SubscribeRequest, SubscribeRequestFilterBlocks, subscribe_update::UpdateOneof,
+};
+
+use crate::{
+    config::load_config_with,
+    retry::RetryPolicy,
+    send::{SendOptions, send_resilient},
+    util::{format_sol, load_keypair, resolve_rpc_url, sol_to_lamports},
+};
+
+// Time span of recent blocks the rolling TPS estimate is computed over
+const TPS_WINDOW: Duration = Duration::from_secs(30);
 
 #[derive(Debug, Serialize, Deserialize)]
-struct Config {
+pub struct Config {
+    // Keypair file, or any other source `load_keypair` accepts
     sender_keypair_file: String,
     recipient_address: String,
     amount_sol: f64,
+    #[serde(default = "default_metrics_interval_secs")]
+    metrics_interval_secs: u64,
+    // Top up the sender from the faucet when it runs dry instead of stopping
+    #[serde(default)]
+    auto_fund: bool,
+}
+
+fn default_metrics_interval_secs() -> u64 {
+    10
+}
+
+impl Config {
+    pub fn new(sender_keypair_file: String, recipient: Pubkey, amount_sol: f64) -> Self {
+        Self {
+            sender_keypair_file,
+            recipient_address: recipient.to_string(),
+            amount_sol,
+            metrics_interval_secs: default_metrics_interval_secs(),
+            auto_fund: false,
+        }
+    }
+}
+
+// Derives cluster health metrics from the stream of block updates
+struct BlockMetrics {
+    blocks: VecDeque<(Instant, u64)>,
+    interval: Duration,
+    last_report: Instant,
+}
+
+impl BlockMetrics {
+    fn new(interval: Duration) -> Self {
+        Self {
+            blocks: VecDeque::new(),
+            interval,
+            last_report: Instant::now(),
+        }
+    }
+
+    fn record(&mut self, slot: u64, tx_count: u64) {
+        let now = Instant::now();
+        let since_previous = self.blocks.back().map(|(at, _)| now.duration_since(*at));
+        self.blocks.push_back((now, tx_count));
+        while self
+            .blocks
+            .front()
+            .is_some_and(|(at, _)| now.duration_since(*at) > TPS_WINDOW)
+        {
+            self.blocks.pop_front();
+        }
+
+        if self.last_report.elapsed() < self.interval {
+            return;
+        }
+        self.last_report = now;
+
+        let block_time = since_previous
+            .map(|d| format!("{} ms", d.as_millis()))
+            .unwrap_or_else(|| "n/a".to_string());
+        println!(
+            "Block metrics: slot {slot}, {tx_count} txs, {block_time} since previous block, {:.1} TPS",
+            self.tps()
+        );
+    }
+
+    fn tps(&self) -> f64 {
+        let (Some((first, _)), Some((last, _))) = (self.blocks.front(), self.blocks.back()) else {
+            return 0.0;
+        };
+        let span = last.duration_since(*first).as_secs_f64();
+        if span == 0.0 {
+            return 0.0;
+        }
+        // Transactions of the oldest block landed before the measured span started
+        let tx_count: u64 = self.blocks.iter().skip(1).map(|(_, count)| count).sum();
+        tx_count as f64 / span
+    }
 }
 
-pub async fn geyser(file: String) -> Result<()> {
-    let config = load_config(&file).await?;
+pub async fn geyser(
+    file: String,
+    strict_config: bool,
+    rpc_url: Option<&str>,
+    retry: RetryPolicy,
+) -> Result<()> {
+    let config: Config = load_config_with(&file, strict_config).await?;
 
     // Create RPC client for transaction submission
-    let rpc_url = dotenv::var("RPC_URL").expect("Missing solana rpc url");
+    let rpc_url = resolve_rpc_url(rpc_url)?;
     let rpc_client = RpcClient::new_with_commitment(rpc_url, CommitmentConfig::confirmed());
 
-    let sender_keypair = Keypair::read_from_file(config.sender_keypair_file)
-        .map_err(|e| anyhow!("failed to read sender keypair: {e:?}"))?;
+    let sender_keypair = load_keypair(&config.sender_keypair_file)
+        .map_err(|e| anyhow!("failed to read sender keypair: {e}"))?;
     if rpc_client
         .get_account(&sender_keypair.pubkey())
         .await
@@ -57,16 +156,24 @@ pub async fn geyser(file: String) -> Result<()> {
     }
 
     // Convert SOL amount to lamports
-    let amount_lamports = (config.amount_sol * LAMPORTS_PER_SOL as f64) as u64;
+    let amount_lamports = sol_to_lamports(config.amount_sol)?;
 
     // Establish connection to Geyser GRPC
-    let x_token = dotenv::var("GEYSER_X_TOKEN").expect("Missing geyser x token");
+    // Self-hosted endpoints may not require a token at all
+    let x_token = dotenv::var("GEYSER_X_TOKEN")
+        .ok()
+        .filter(|token| !token.is_empty());
 
     let endpoint = dotenv::var("GEYSER_ENDPOINT").expect("Missing geyser endpoint");
     let tls_config = ClientTlsConfig::new().with_native_roots();
-    let builder = GeyserGrpcClient::build_from_shared(endpoint)?
-        .tls_config(tls_config)?
-        .x_token(Some(x_token))?;
+    let mut builder = GeyserGrpcClient::build_from_shared(endpoint)?.tls_config(tls_config)?;
+    match x_token {
+        Some(x_token) => {
+            println!("Authenticating to geyser with x-token");
+            builder = builder.x_token(Some(x_token))?;
+        }
+        None => println!("GEYSER_X_TOKEN is not set, connecting to geyser without authentication"),
+    }
     let mut client = builder.connect().await?;
 
     // Set up block subscription
@@ -84,10 +191,16 @@ pub async fn geyser(file: String) -> Result<()> {
         .await?;
     println!("Subscription set up successfully. Monitoring for new blocks...");
 
+    let mut metrics = BlockMetrics::new(Duration::from_secs(config.metrics_interval_secs));
+
     // Monitor for new blocks
     while let Some(block_update) = block_subscription.next().await {
         match block_update {
             Ok(update) => {
+                if let Some(UpdateOneof::Block(block)) = &update.update_oneof {
+                    metrics.record(block.slot, block.executed_transaction_count);
+                }
+
                 println!("New block detected: slot {}", update.created_at.unwrap());
 
                 // Send transaction
@@ -96,6 +209,7 @@ pub async fn geyser(file: String) -> Result<()> {
                     &sender_keypair,
                     &recipient,
                     amount_lamports,
+                    retry,
                 )
                 .await
                 {
@@ -103,10 +217,22 @@ pub async fn geyser(file: String) -> Result<()> {
                         println!("Transaction sent successfully! Signature: {}", signature);
 
                         let balance = rpc_client.get_balance(&recipient).await.unwrap();
-                        println!(
-                            "Recipient balance: {}",
-                            balance as f64 / LAMPORTS_PER_SOL as f64
-                        );
+                        println!("Recipient balance: {} SOL", format_sol(balance));
+                    }
+                    Err(err) if is_insufficient_funds(&err) => {
+                        // Every following block would fail the same way, so don't keep firing
+                        if !config.auto_fund {
+                            bail!(
+                                "Sender {} ran out of funds, stopping. Fund it or set `auto_fund: true` on faucet clusters.",
+                                sender_keypair.pubkey()
+                            );
+                        }
+                        println!("Sender ran out of funds, requesting airdrop...");
+                        let signature = rpc_client
+                            .request_airdrop(&sender_keypair.pubkey(), LAMPORTS_PER_SOL / 100)
+                            .await?;
+                        rpc_client.poll_for_signature(&signature).await?;
+                        println!("airdrop completed");
                     }
                     Err(err) => eprintln!("Failed to send transaction: {}", err),
                 }
@@ -122,31 +248,47 @@ pub async fn geyser(file: String) -> Result<()> {
     Ok(())
 }
 
-async fn load_config(path: &str) -> Result<Config> {
-    let config: Config = serde_yaml::from_str(&fs::read_to_string(path).await?)?;
-    Ok(config)
-}
-
 async fn send_sol_transaction(
     rpc_client: &RpcClient,
     sender: &Keypair,
     recipient: &Pubkey,
     amount_lamports: u64,
+    retry: RetryPolicy,
 ) -> Result<String> {
     // Create transfer instruction
     let instruction = system_instruction::transfer(&sender.pubkey(), recipient, amount_lamports);
 
-    // Get recent blockhash
-    let blockhash = rpc_client.get_latest_blockhash().await?;
-
-    // Create and sign transaction
-    let message = Message::new(&[instruction], Some(&sender.pubkey()));
-    let transaction = Transaction::new(&[sender], message, blockhash);
-
-    // Send transaction
-    let signature = rpc_client
-        .send_and_confirm_transaction(&transaction)
-        .await?;
+    // Send transaction, resending it if its blockhash expires first
+    let signature = send_resilient(
+        rpc_client,
+        &[instruction],
+        &[sender],
+        &sender.pubkey(),
+        SendOptions {
+            retry,
+            ..SendOptions::default()
+        },
+    )
+    .await?
+    .confirmed()?;
 
     Ok(signature.to_string())
 }
+
+fn is_insufficient_funds(err: &anyhow::Error) -> bool {
+    // Rejected in preflight, or landed and failed
+    let Some(tx_err) = err
+        .downcast_ref::<ClientError>()
+        .and_then(ClientError::get_transaction_error)
+        .or_else(|| err.downcast_ref::<TransactionError>().cloned())
+    else {
+        return false;
+    };
+    match tx_err {
+        TransactionError::InsufficientFundsForFee => true,
+        TransactionError::InstructionError(_, InstructionError::Custom(code)) => {
+            code == SystemError::ResultWithNegativeLamports as u32
+        }
+        _ => false,
+    }
+}
@@ -0,0 +1,104 @@
+use std::str::FromStr;
+
+use anyhow::{Result, bail};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use clap::ValueEnum;
+use serde::Serialize;
+use solana_client::{nonblocking::rpc_client::RpcClient, rpc_config::RpcTransactionConfig};
+use solana_sdk::{
+    commitment_config::{CommitmentConfig, CommitmentLevel},
+    pubkey::Pubkey,
+    signature::Signature,
+};
+use solana_transaction_status_client_types::UiTransactionEncoding;
+
+use crate::util::resolve_rpc_url;
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum TxEncoding {
+    Json,
+    // Raw transaction bytes, enough to reconstruct and resend it
+    Base64,
+    // Human-readable instruction data
+    #[value(name = "jsonParsed")]
+    JsonParsed,
+}
+
+impl From<TxEncoding> for UiTransactionEncoding {
+    fn from(encoding: TxEncoding) -> Self {
+        match encoding {
+            TxEncoding::Json => Self::Json,
+            TxEncoding::Base64 => Self::Base64,
+            TxEncoding::JsonParsed => Self::JsonParsed,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct AccountDump {
+    pubkey: String,
+    owner: String,
+    lamports: u64,
+    executable: bool,
+    rent_epoch: u64,
+    // Base64-encoded account data
+    data: String,
+}
+
+// Prints a transaction as returned by the cluster
+pub async fn tx(
+    signature: String,
+    commitment: CommitmentLevel,
+    encoding: TxEncoding,
+    rpc_url: Option<&str>,
+) -> Result<()> {
+    let signature = Signature::from_str(&signature)?;
+
+    // Connect to Solana network
+    let rpc_url = resolve_rpc_url(rpc_url)?;
+    let rpc_client = RpcClient::new(rpc_url);
+
+    let config = RpcTransactionConfig {
+        encoding: Some(encoding.into()),
+        commitment: Some(CommitmentConfig { commitment }),
+        max_supported_transaction_version: Some(0),
+    };
+    let transaction = rpc_client
+        .get_transaction_with_config(&signature, config)
+        .await?;
+    println!("{}", serde_json::to_string_pretty(&transaction)?);
+
+    Ok(())
+}
+
+// Prints an account with its raw data
+pub async fn dump(
+    address: String,
+    commitment: CommitmentLevel,
+    rpc_url: Option<&str>,
+) -> Result<()> {
+    let pubkey = Pubkey::from_str(&address)?;
+
+    // Connect to Solana network
+    let rpc_url = resolve_rpc_url(rpc_url)?;
+    let rpc_client = RpcClient::new(rpc_url);
+
+    let Some(account) = rpc_client
+        .get_account_with_commitment(&pubkey, CommitmentConfig { commitment })
+        .await?
+        .value
+    else {
+        bail!("account {pubkey} not found");
+    };
+    let dump = AccountDump {
+        pubkey: pubkey.to_string(),
+        owner: account.owner.to_string(),
+        lamports: account.lamports,
+        executable: account.executable,
+        rent_epoch: account.rent_epoch,
+        data: STANDARD.encode(&account.data),
+    };
+    println!("{}", serde_json::to_string_pretty(&dump)?);
+
+    Ok(())
+}
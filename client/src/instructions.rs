@@ -0,0 +1,53 @@
+use program::instruction::DepositInstruction;
+use solana_program::{
+    instruction::{AccountMeta, Instruction},
+    pubkey::Pubkey,
+};
+
+// Accounts expected by every deposit program instruction, in processor order.
+fn deposit_accounts(user: &Pubkey, pda: &Pubkey) -> Vec<AccountMeta> {
+    vec![
+        AccountMeta::new(*user, true),
+        AccountMeta::new(*pda, false),
+        AccountMeta::new_readonly(solana_program::system_program::id(), false),
+    ]
+}
+
+pub fn initialize_ix(program_id: &Pubkey, user: &Pubkey, pda: &Pubkey) -> Instruction {
+    Instruction::new_with_borsh(
+        *program_id,
+        &DepositInstruction::Initialize,
+        deposit_accounts(user, pda),
+    )
+}
+
+pub fn deposit_ix(program_id: &Pubkey, user: &Pubkey, pda: &Pubkey, amount: u64) -> Instruction {
+    Instruction::new_with_borsh(
+        *program_id,
+        &DepositInstruction::Deposit { amount },
+        deposit_accounts(user, pda),
+    )
+}
+
+pub fn change_owner_ix(
+    program_id: &Pubkey,
+    owner: &Pubkey,
+    pda: &Pubkey,
+    new_owner: &Pubkey,
+) -> Instruction {
+    Instruction::new_with_borsh(
+        *program_id,
+        &DepositInstruction::ChangeOwner {
+            new_owner: *new_owner,
+        },
+        deposit_accounts(owner, pda),
+    )
+}
+
+pub fn withdraw_ix(program_id: &Pubkey, user: &Pubkey, pda: &Pubkey, amount: u64) -> Instruction {
+    Instruction::new_with_borsh(
+        *program_id,
+        &DepositInstruction::Withdraw { amount },
+        deposit_accounts(user, pda),
+    )
+}
@@ -1,49 +1,559 @@
+use std::process;
+
 use anyhow::Result;
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use program::state::DepositAccount;
+use solana_sdk::commitment_config::CommitmentLevel;
+
+use crate::{
+    depository::DepositoryAction,
+    get_balances::{BalanceMode, OutputFormat},
+    inspect::TxEncoding,
+    priority::PriorityFee,
+    recipients::SeededRecipients,
+    rent::AccountKind,
+    retry::RetryPolicy,
+    transfer::ResultsFormat,
+    util::PartialFailure,
+};
 
+mod airdrop;
+mod config;
+mod depository;
 mod get_balances;
 mod geyser;
+mod inspect;
+mod instructions;
+mod merkle;
 mod prepare;
+mod priority;
+mod recipients;
+mod rent;
+mod retry;
+mod selftest;
+mod send;
 mod transfer;
+mod util;
 
 #[derive(Parser)]
+struct Cli {
+    #[clap(subcommand)]
+    command: CliCommands,
+    /// RPC endpoint to use instead of the RPC_URL environment variable.
+    #[clap(long, global = true)]
+    rpc_url: Option<String>,
+    #[clap(flatten)]
+    retry: RetryPolicy,
+}
+
+#[derive(Subcommand)]
 enum CliCommands {
     GetBalances {
+        /// Wallets config, or `-` to read whitespace-separated addresses from stdin.
         #[clap(short, long, default_value_t = String::from("wallets.yaml"))]
         file: String,
+        /// Wallets config as a positional argument, `-` reads addresses from stdin.
+        #[clap(value_name = "FILE", conflicts_with = "file")]
+        input: Option<String>,
+        /// Check recipients derived with `seed:<STR>:<COUNT>` instead of the wallets file.
+        #[clap(long)]
+        recipients: Option<SeededRecipients>,
+        /// Include base64-encoded raw account data in the output.
+        #[clap(long)]
+        raw: bool,
+        /// How balances are requested from the RPC node.
+        #[clap(long, value_enum, default_value_t = BalanceMode::MultipleAccounts)]
+        mode: BalanceMode,
+        /// Maximum number of balance requests in flight at once.
+        #[clap(long, default_value_t = 50)]
+        max_concurrency: usize,
+        /// Format of the balances file.
+        #[clap(long, value_enum, default_value_t = OutputFormat::Yaml)]
+        format: OutputFormat,
+        /// Where to write the balances, `balances.<format>` by default.
+        #[clap(long, value_name = "PATH")]
+        output: Option<String>,
+        /// Leave wallets holding fewer lamports than this out of the output.
+        #[clap(long, default_value_t = 0)]
+        min_lamports: u64,
+        /// Also write the skipped invalid addresses to PATH as YAML.
+        #[clap(long, value_name = "PATH")]
+        invalid_output: Option<String>,
+        /// Reject config fields the command doesn't know instead of ignoring them.
+        #[clap(long)]
+        strict_config: bool,
     },
     Transfer {
         #[clap(short, long, default_value_t = String::from("transfer.yaml"))]
         file: String,
+        /// Send to recipients derived with `seed:<STR>:<COUNT>` instead of the configured ones.
+        #[clap(long)]
+        recipients: Option<SeededRecipients>,
+        /// Record the commitment level and confirmation depth of every transfer.
+        #[clap(long)]
+        detailed: bool,
+        /// Run the batch against an in-process bank instead of a cluster.
+        #[clap(long)]
+        simulate_bank: bool,
+        /// Append every result to FILE as a JSON line as soon as it completes.
+        #[clap(long, value_name = "FILE")]
+        stream: Option<String>,
+        /// Write Prometheus textfile metrics for the run to FILE.
+        #[clap(long, value_name = "FILE")]
+        metrics_file: Option<String>,
+        /// Write all results to PATH once the batch is done.
+        #[clap(long, value_name = "PATH")]
+        output: Option<String>,
+        /// Format of the results written with --output.
+        #[clap(long, value_enum, default_value_t = ResultsFormat::Json)]
+        format: ResultsFormat,
+        /// Write the failed transfers to PATH as a config that retries just those.
+        #[clap(long, value_name = "PATH")]
+        failures_out: Option<String>,
+        /// Exit with the partial-failure code when fewer than PERCENT of the operations succeed.
+        #[clap(long, value_name = "PERCENT")]
+        min_success_rate: Option<f64>,
+        /// Compute unit price in micro-lamports, or `auto[:pNN]` to estimate it from recent fees.
+        #[clap(long)]
+        priority: Option<PriorityFee>,
+        /// Compute unit limit requested by every transfer, the runtime default when omitted.
+        #[clap(long)]
+        compute_unit_limit: Option<u32>,
+        /// Fetch a blockhash for every transfer instead of sharing one across the batch.
+        #[clap(long)]
+        fresh_blockhash_per_tx: bool,
+        /// Maximum number of transfers in flight at once.
+        #[clap(long, default_value_t = transfer::TRANSFER_CONCURRENCY)]
+        max_concurrency: usize,
+        /// Simulate every transfer on the cluster instead of sending it.
+        #[clap(long)]
+        dry_run: bool,
+        /// Submit transfers without simulating them first, halving the RPC load.
+        #[clap(long)]
+        skip_preflight: bool,
+        /// Reject config fields the command doesn't know instead of ignoring them.
+        #[clap(long)]
+        strict_config: bool,
+    },
+    Distribute {
+        /// Keypair file, JSON byte array or base58-encoded secret of the funding wallet.
+        #[clap(long)]
+        source_keypair: String,
+        #[clap(short, long, default_value_t = String::from("wallets.yaml"))]
+        wallets_file: String,
+        #[clap(long)]
+        amount_sol: f64,
+        #[clap(long)]
+        detailed: bool,
+        /// Compute unit price in micro-lamports, or `auto[:pNN]` to estimate it from recent fees.
+        #[clap(long)]
+        priority: Option<PriorityFee>,
+        /// Compute unit limit requested by every transfer, the runtime default when omitted.
+        #[clap(long)]
+        compute_unit_limit: Option<u32>,
+        /// Fetch a blockhash for every transfer instead of sharing one across the batch.
+        #[clap(long)]
+        fresh_blockhash_per_tx: bool,
+        /// Reject config fields the command doesn't know instead of ignoring them.
+        #[clap(long)]
+        strict_config: bool,
+    },
+    AirdropAll {
+        #[clap(short, long, default_value_t = String::from("wallets.yaml"))]
+        wallets_file: String,
+        /// Amount to airdrop to every wallet.
+        #[clap(long)]
+        sol: f64,
+        #[clap(long, default_value_t = 10)]
+        max_concurrency: usize,
+        /// Exit with the partial-failure code when fewer than PERCENT of the operations succeed.
+        #[clap(long, value_name = "PERCENT")]
+        min_success_rate: Option<f64>,
     },
     Geyser {
         #[clap(short, long, default_value_t = String::from("geyser.yaml"))]
         file: String,
+        /// Reject config fields the command doesn't know instead of ignoring them.
+        #[clap(long)]
+        strict_config: bool,
     },
     Prepare {
         #[clap(short, long, default_value_t = String::from("wallets.yaml"))]
         balances_config: String,
+        /// Base58 secrets of the generated wallets, keep this file private.
+        #[clap(long, default_value_t = String::from("wallet_keypairs.yaml"))]
+        keypairs_file: String,
+        /// Number of wallets to generate and fund.
+        #[clap(long, default_value_t = 500)]
+        count: usize,
         #[clap(short, long, default_value_t = String::from("transfer.yaml"))]
         transfer_config: String,
         #[clap(short, long, default_value_t = String::from("geyser.yaml"))]
         geyser_config: String,
+        /// Also write every generated keypair to `<DIR>/<pubkey>.json`.
+        #[clap(long, value_name = "DIR")]
+        split_dir: Option<String>,
+    },
+    Depository {
+        #[clap(subcommand)]
+        action: DepositoryAction,
+        /// Payer keypair file, created if it doesn't exist.
+        #[clap(long, global = true, default_value_t = String::from("dep_test_account.json"))]
+        keypair: String,
+        /// Compute unit price in micro-lamports, or `auto[:pNN]` to estimate it from recent fees.
+        #[clap(long, global = true)]
+        priority: Option<PriorityFee>,
+    },
+    ChangeOwner {
+        #[clap(long)]
+        program_id: String,
+        /// Keypair file of the current owner.
+        #[clap(long, default_value_t = String::from("dep_test_account.json"))]
+        keypair: String,
+        /// Deposit account to hand over, derived from the owner's key by default.
+        #[clap(long)]
+        account: Option<String>,
+        #[clap(long)]
+        new_owner: String,
+    },
+    DepositList {
+        #[clap(long)]
+        program_id: String,
+        /// Only scan accounts of this many bytes.
+        #[clap(long, default_value_t = DepositAccount::LEN as u64)]
+        data_size: u64,
+        /// Re-scan and redraw the table every SECS seconds.
+        #[clap(long, value_name = "SECS")]
+        watch: Option<u64>,
+    },
+    DepositSnapshot {
+        #[clap(long)]
+        program_id: String,
+        /// Only scan accounts of this many bytes.
+        #[clap(long, default_value_t = DepositAccount::LEN as u64)]
+        data_size: u64,
+        #[clap(long, default_value_t = String::from("deposit_snapshot.json"))]
+        out: String,
+    },
+    SeedDeposits {
+        #[clap(long)]
+        program_id: String,
+        #[clap(long, default_value_t = 10)]
+        count: usize,
+        #[clap(long, default_value_t = 0.01)]
+        amount_sol: f64,
+        #[clap(long, default_value_t = 10)]
+        max_concurrency: usize,
+        /// Exit with the partial-failure code when fewer than PERCENT of the operations succeed.
+        #[clap(long, value_name = "PERCENT")]
+        min_success_rate: Option<f64>,
+    },
+    SelfTest {
+        #[clap(long)]
+        program_id: String,
+        #[clap(short, long, default_value_t = String::from("selftest.yaml"))]
+        file: String,
+    },
+    Tx {
+        signature: String,
+        #[clap(long, default_value_t = CommitmentLevel::Confirmed)]
+        commitment: CommitmentLevel,
+        #[clap(long, value_enum, default_value_t = TxEncoding::JsonParsed)]
+        encoding: TxEncoding,
+    },
+    Dump {
+        address: String,
+        #[clap(long, default_value_t = CommitmentLevel::Confirmed)]
+        commitment: CommitmentLevel,
+    },
+    RentEstimate {
+        /// Account data size in bytes.
+        #[clap(long)]
+        bytes: Option<usize>,
+        /// Use the size of a program account instead of `--bytes`.
+        #[clap(long = "for", value_enum)]
+        kind: Option<AccountKind>,
+        /// Number of leaves to size a merkle account for.
+        #[clap(long, default_value_t = 0)]
+        leaves: usize,
+        /// Number of accounts to budget for.
+        #[clap(long, default_value_t = 1)]
+        count: u64,
+    },
+    MerkleRoot {
+        #[clap(short, long, default_value_t = String::from("leaves.yaml"))]
+        leaves_file: String,
+    },
+    MerkleVerify {
+        /// Id of the program built with the `merkle` feature.
+        #[clap(long)]
+        program_id: String,
+        #[clap(long)]
+        account: String,
+        #[clap(short, long, default_value_t = String::from("leaves.yaml"))]
+        leaves_file: String,
+    },
+    MerkleBatchInsert {
+        /// Id of the program built with the `merkle` feature.
+        #[clap(long)]
+        program_id: String,
+        #[clap(long)]
+        account: String,
+        #[clap(short, long, default_value_t = String::from("leaves.yaml"))]
+        leaves_file: String,
+        #[clap(long, default_value_t = 200_000)]
+        cu_budget: u32,
+        /// Fail a batch holding a leaf that is already in the tree.
+        #[clap(long)]
+        reject_duplicates: bool,
+        #[clap(long, default_value_t = String::from("payer.json"))]
+        payer: String,
     },
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     dotenv::dotenv()?;
-    let args = CliCommands::parse();
+    let cli = Cli::parse();
+
+    let result = run(cli).await;
+    if let Err(err) = &result
+        && let Some(partial_failure) = err.downcast_ref::<PartialFailure>()
+    {
+        eprintln!("Error: {partial_failure}");
+        process::exit(PartialFailure::EXIT_CODE);
+    }
+    result
+}
+
+async fn run(cli: Cli) -> Result<()> {
+    let retry = cli.retry;
+    let rpc_url = cli.rpc_url.as_deref();
 
-    match args {
-        CliCommands::GetBalances { file } => get_balances::get_balances(file).await?,
-        CliCommands::Transfer { file } => transfer::transfer(file).await?,
-        CliCommands::Geyser { file } => geyser::geyser(file).await?,
+    match cli.command {
+        CliCommands::GetBalances {
+            file,
+            input,
+            recipients,
+            raw,
+            mode,
+            max_concurrency,
+            format,
+            output,
+            invalid_output,
+            min_lamports,
+            strict_config,
+        } => {
+            let options = get_balances::BalanceOptions {
+                raw,
+                mode,
+                max_concurrency,
+                format,
+                min_lamports,
+                strict_config,
+                retry,
+            };
+            let file = input.unwrap_or(file);
+            get_balances::get_balances(file, recipients, output, invalid_output, rpc_url, options)
+                .await?
+        }
+        CliCommands::Transfer {
+            file,
+            recipients,
+            detailed,
+            simulate_bank,
+            stream,
+            metrics_file,
+            output,
+            format,
+            failures_out,
+            min_success_rate,
+            priority,
+            compute_unit_limit,
+            fresh_blockhash_per_tx,
+            max_concurrency,
+            dry_run,
+            skip_preflight,
+            strict_config,
+        } => {
+            let options = transfer::TransferOptions {
+                detailed,
+                simulate_bank,
+                dry_run,
+                skip_preflight,
+                priority,
+                compute_unit_limit,
+                fresh_blockhash_per_tx,
+                max_concurrency,
+                strict_config,
+                retry,
+            };
+            let outputs = transfer::TransferOutputs {
+                stream,
+                metrics_file,
+                output,
+                format,
+                failures_out,
+            };
+            transfer::transfer(
+                file,
+                recipients,
+                outputs,
+                min_success_rate,
+                rpc_url,
+                options,
+            )
+            .await?
+        }
+        CliCommands::Distribute {
+            source_keypair,
+            wallets_file,
+            amount_sol,
+            detailed,
+            priority,
+            compute_unit_limit,
+            fresh_blockhash_per_tx,
+            strict_config,
+        } => {
+            transfer::distribute(
+                source_keypair,
+                wallets_file,
+                amount_sol,
+                rpc_url,
+                transfer::TransferOptions {
+                    detailed,
+                    priority,
+                    compute_unit_limit,
+                    fresh_blockhash_per_tx,
+                    strict_config,
+                    retry,
+                    ..Default::default()
+                },
+            )
+            .await?
+        }
+        CliCommands::AirdropAll {
+            wallets_file,
+            sol,
+            max_concurrency,
+            min_success_rate,
+        } => {
+            airdrop::airdrop_all(
+                wallets_file,
+                sol,
+                max_concurrency,
+                min_success_rate,
+                rpc_url,
+                retry,
+            )
+            .await?
+        }
+        CliCommands::Geyser {
+            file,
+            strict_config,
+        } => geyser::geyser(file, strict_config, rpc_url, retry).await?,
         CliCommands::Prepare {
             balances_config,
+            keypairs_file,
+            count,
             transfer_config,
             geyser_config,
-        } => prepare::prepare(&balances_config, transfer_config, geyser_config).await?,
+            split_dir,
+        } => {
+            let options = prepare::PrepareOptions { count, retry };
+            prepare::prepare(
+                &balances_config,
+                &keypairs_file,
+                transfer_config,
+                geyser_config,
+                split_dir.as_deref(),
+                rpc_url,
+                options,
+            )
+            .await?
+        }
+        CliCommands::Depository {
+            action,
+            keypair,
+            priority,
+        } => depository::depository(&keypair, priority, action, rpc_url, retry).await?,
+        CliCommands::ChangeOwner {
+            program_id,
+            keypair,
+            account,
+            new_owner,
+        } => {
+            depository::change_owner(program_id, &keypair, account, new_owner, rpc_url, retry)
+                .await?
+        }
+        CliCommands::DepositList {
+            program_id,
+            data_size,
+            watch,
+        } => depository::deposit_list(program_id, data_size, watch, rpc_url).await?,
+        CliCommands::DepositSnapshot {
+            program_id,
+            data_size,
+            out,
+        } => depository::deposit_snapshot(program_id, data_size, out, rpc_url).await?,
+        CliCommands::SeedDeposits {
+            program_id,
+            count,
+            amount_sol,
+            max_concurrency,
+            min_success_rate,
+        } => {
+            depository::seed_deposits(
+                program_id,
+                count,
+                amount_sol,
+                max_concurrency,
+                min_success_rate,
+                rpc_url,
+                retry,
+            )
+            .await?
+        }
+        CliCommands::SelfTest { program_id, file } => {
+            selftest::self_test(program_id, file, rpc_url, retry).await?
+        }
+        CliCommands::Tx {
+            signature,
+            commitment,
+            encoding,
+        } => inspect::tx(signature, commitment, encoding, rpc_url).await?,
+        CliCommands::Dump {
+            address,
+            commitment,
+        } => inspect::dump(address, commitment, rpc_url).await?,
+        CliCommands::RentEstimate {
+            bytes,
+            kind,
+            leaves,
+            count,
+        } => rent::rent_estimate(bytes, kind, leaves, count, rpc_url).await?,
+        CliCommands::MerkleRoot { leaves_file } => merkle::merkle_root(leaves_file).await?,
+        CliCommands::MerkleVerify {
+            program_id,
+            account,
+            leaves_file,
+        } => merkle::merkle_verify(program_id, account, leaves_file, rpc_url).await?,
+        CliCommands::MerkleBatchInsert {
+            program_id,
+            account,
+            leaves_file,
+            cu_budget,
+            reject_duplicates,
+            payer,
+        } => {
+            let options = merkle::InsertOptions {
+                cu_budget,
+                reject_duplicates,
+                retry,
+            };
+            merkle::merkle_batch_insert(program_id, account, leaves_file, payer, rpc_url, options)
+                .await?
+        }
     }
     Ok(())
 }
@@ -0,0 +1,323 @@
+use std::str::FromStr;
+
+use anyhow::{Result, anyhow, bail};
+use borsh::BorshDeserialize;
+use program::mtree::{MAX_LEAVES, MerkleInstruction, MerkleTree};
+use solana_client::nonblocking::rpc_client::RpcClient;
+use solana_program::{
+    instruction::{AccountMeta, Instruction},
+    pubkey::Pubkey,
+};
+use solana_sdk::{
+    commitment_config::CommitmentConfig, compute_budget::ComputeBudgetInstruction,
+    message::Message, packet::PACKET_DATA_SIZE, signature::Signer,
+};
+
+use crate::{
+    config::load_config,
+    retry::RetryPolicy,
+    send::{SendOptions, send_resilient},
+    util::{load_keypair, resolve_rpc_url},
+};
+
+// Rough compute cost model of `InsertLeaves`: the whole tree is deserialized, rehashed
+// and serialized back on every call, and each new leaf's data is hashed once.
+const BASE_COMPUTE_UNITS: u64 = 10_000;
+const COMPUTE_UNITS_PER_LEAF: u64 = 250;
+const COMPUTE_UNITS_PER_DATA_BYTE: u64 = 1;
+
+#[derive(Clone, Copy, Debug)]
+pub struct InsertOptions {
+    // Compute unit limit requested for every batch transaction
+    pub cu_budget: u32,
+    // Fail a batch holding a leaf that is already in the tree
+    pub reject_duplicates: bool,
+    pub retry: RetryPolicy,
+}
+
+pub async fn merkle_root(leaves_file: String) -> Result<()> {
+    // Read leaves list
+    let leaves: Vec<String> = load_config(&leaves_file).await?;
+
+    // Build the tree with the same hashing the program uses
+    let mut tree = MerkleTree::default();
+    for leaf in &leaves {
+        tree.insert_leaf(leaf.as_bytes());
+    }
+
+    println!("Leaves: {}", tree.leaves.len());
+    println!("Depth: {}", tree.depth());
+    println!("Root: {}", hex::encode(tree.root));
+
+    Ok(())
+}
+
+// Compares the root built from the leaves file with the one stored on chain
+pub async fn merkle_verify(
+    program_id: String,
+    account: String,
+    leaves_file: String,
+    rpc_url: Option<&str>,
+) -> Result<()> {
+    let program_id = Pubkey::from_str(&program_id)?;
+    let account = Pubkey::from_str(&account)?;
+    let leaves: Vec<String> = load_config(&leaves_file).await?;
+
+    let mut local = MerkleTree::default();
+    for leaf in &leaves {
+        local.insert_leaf(leaf.as_bytes());
+    }
+
+    // Connect to Solana network
+    let rpc_url = resolve_rpc_url(rpc_url)?;
+    let rpc_client = RpcClient::new_with_commitment(rpc_url, CommitmentConfig::confirmed());
+
+    let onchain_account = rpc_client.get_account(&account).await?;
+    if onchain_account.owner != program_id {
+        bail!(
+            "account {account} is not owned by program {program_id} (owner: {})",
+            onchain_account.owner
+        );
+    }
+    let onchain = MerkleTree::deserialize(&mut onchain_account.data.as_slice())?;
+
+    println!(
+        "Local root:    {} ({} leaves)",
+        hex::encode(local.root),
+        local.leaves.len()
+    );
+    println!(
+        "On-chain root: {} ({} leaves)",
+        hex::encode(onchain.root),
+        onchain.leaves.len()
+    );
+
+    if local.root == onchain.root {
+        println!("Roots match");
+        return Ok(());
+    }
+
+    println!("Roots mismatch");
+    match first_divergent_leaf(&local.leaves, &onchain.leaves) {
+        Some(index) if index < local.leaves.len().min(onchain.leaves.len()) => println!(
+            "First divergent leaf: {index} (local {}, on-chain {})",
+            hex::encode(local.leaves[index]),
+            hex::encode(onchain.leaves[index])
+        ),
+        Some(index) => println!(
+            "Leaves agree up to {index}, then only the {} tree continues",
+            if local.leaves.len() > index {
+                "local"
+            } else {
+                "on-chain"
+            }
+        ),
+        // Same leaves but a different root means the stored root is stale
+        None => println!("All leaves match, the on-chain root is out of date"),
+    }
+
+    Ok(())
+}
+
+// Index of the first leaf the two trees disagree on, counting a missing leaf as a difference
+pub fn first_divergent_leaf(left: &[[u8; 32]], right: &[[u8; 32]]) -> Option<usize> {
+    left.iter()
+        .zip(right)
+        .position(|(left, right)| left != right)
+        .or_else(|| (left.len() != right.len()).then(|| left.len().min(right.len())))
+}
+
+pub async fn merkle_batch_insert(
+    program_id: String,
+    account: String,
+    leaves_file: String,
+    payer: String,
+    rpc_url: Option<&str>,
+    options: InsertOptions,
+) -> Result<()> {
+    let InsertOptions {
+        cu_budget,
+        reject_duplicates,
+        retry,
+    } = options;
+    let program_id = Pubkey::from_str(&program_id)?;
+    let account = Pubkey::from_str(&account)?;
+    let leaves: Vec<String> = load_config(&leaves_file).await?;
+    let payer = load_keypair(&payer).map_err(|e| anyhow!("failed to read payer keypair: {e}"))?;
+
+    // Connect to Solana network
+    let rpc_url = resolve_rpc_url(rpc_url)?;
+    let rpc_client = RpcClient::new_with_commitment(rpc_url, CommitmentConfig::confirmed());
+
+    // Batch sizes depend on how many leaves the tree already holds
+    let account_data = rpc_client.get_account_data(&account).await?;
+    let tree = MerkleTree::deserialize(&mut account_data.as_slice())?;
+    let batches = split_into_batches(
+        tree.leaves.len(),
+        &leaves,
+        cu_budget as u64,
+        reject_duplicates,
+    )?;
+    println!(
+        "Inserting {} leaves into a tree of {} in {} transactions",
+        leaves.len(),
+        tree.leaves.len(),
+        batches.len()
+    );
+
+    for (i, batch) in batches.iter().enumerate() {
+        let instruction = insert_leaves_ix(program_id, account, batch, reject_duplicates);
+        let signature = send_resilient(
+            &rpc_client,
+            &[instruction],
+            &[&payer],
+            &payer.pubkey(),
+            SendOptions {
+                compute_unit_limit: Some(cu_budget),
+                retry,
+                ..SendOptions::default()
+            },
+        )
+        .await?
+        .confirmed()?;
+        println!(
+            "Batch {}/{} ({} leaves) inserted: {signature}",
+            i + 1,
+            batches.len(),
+            batch.len()
+        );
+    }
+
+    Ok(())
+}
+
+fn insert_leaves_ix(
+    program_id: Pubkey,
+    account: Pubkey,
+    batch: &[String],
+    reject_duplicates: bool,
+) -> Instruction {
+    Instruction::new_with_borsh(
+        program_id,
+        &MerkleInstruction::InsertLeaves {
+            leaves: batch.iter().map(|leaf| leaf.as_bytes().to_vec()).collect(),
+            reject_duplicates,
+        },
+        vec![AccountMeta::new(account, false)],
+    )
+}
+
+// Size of the signed transaction inserting `batch`: the signature, a message keyed by the
+// payer, the tree account and both program ids, the blockhash, the compute unit limit
+// instruction and the Borsh-encoded leaves, each behind its 4 byte length prefix
+pub fn batch_transaction_size(batch: &[String], reject_duplicates: bool) -> usize {
+    // Only the number of distinct keys matters, not their values
+    let [payer, program_id, account] = [1, 2, 3].map(|byte| Pubkey::new_from_array([byte; 32]));
+    let message = Message::new(
+        &[
+            ComputeBudgetInstruction::set_compute_unit_limit(0),
+            insert_leaves_ix(program_id, account, batch, reject_duplicates),
+        ],
+        Some(&payer),
+    );
+    1 + 64 + message.serialize().len()
+}
+
+pub fn estimate_insert_cost(leaf_count: usize, batch: &[String]) -> u64 {
+    let data_bytes: usize = batch.iter().map(String::len).sum();
+    BASE_COMPUTE_UNITS
+        + (leaf_count + batch.len()) as u64 * COMPUTE_UNITS_PER_LEAF
+        + data_bytes as u64 * COMPUTE_UNITS_PER_DATA_BYTE
+}
+
+// Greedily packs leaves into the largest batches that stay under the compute budget and
+// fit into a packet, accounting for the tree growing with every batch.
+pub fn split_into_batches(
+    mut leaf_count: usize,
+    leaves: &[String],
+    cu_budget: u64,
+    reject_duplicates: bool,
+) -> Result<Vec<&[String]>> {
+    let fits = |leaf_count: usize, batch: &[String]| {
+        leaf_count + batch.len() <= MAX_LEAVES
+            && estimate_insert_cost(leaf_count, batch) <= cu_budget
+            && batch_transaction_size(batch, reject_duplicates) <= PACKET_DATA_SIZE
+    };
+
+    let mut batches = Vec::new();
+    let mut start = 0;
+    while start < leaves.len() {
+        let mut end = start;
+        while end < leaves.len() && fits(leaf_count, &leaves[start..=end]) {
+            end += 1;
+        }
+        if end == start && leaf_count >= MAX_LEAVES {
+            bail!("tree is full, it holds at most {MAX_LEAVES} leaves");
+        }
+        if end == start {
+            bail!("leaf {start} doesn't fit into a transaction within {cu_budget} compute units");
+        }
+
+        batches.push(&leaves[start..end]);
+        leaf_count += end - start;
+        start = end;
+    }
+    Ok(batches)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn batch_transaction_size_counts_overhead_and_prefixes() {
+        // Signature, header, 4 keys, blockhash, compute unit limit and an empty insert
+        assert_eq!(batch_transaction_size(&[], false), 248);
+        // Every leaf adds its bytes behind a 4 byte length prefix
+        assert_eq!(
+            batch_transaction_size(&["abc".to_string()], false),
+            248 + 4 + 3
+        );
+    }
+
+    #[test]
+    fn split_into_batches_fills_packets() {
+        let leaves: Vec<String> = (0..40).map(|i| format!("{i:0>100}")).collect();
+        let batches = split_into_batches(0, &leaves, u64::MAX, false).unwrap();
+
+        assert_eq!(batches.iter().map(|batch| batch.len()).sum::<usize>(), 40);
+        let mut start = 0;
+        for batch in &batches {
+            assert!(batch_transaction_size(batch, false) <= PACKET_DATA_SIZE);
+            // Taking the next leaf as well would overflow the packet
+            start += batch.len();
+            if start < leaves.len() {
+                let grown = &leaves[start - batch.len()..=start];
+                assert!(batch_transaction_size(grown, false) > PACKET_DATA_SIZE);
+            }
+        }
+    }
+
+    #[test]
+    fn split_into_batches_accounts_for_tree_growth() {
+        let leaves = vec!["a".to_string(), "b".to_string(), "c".to_string()];
+        let cu_budget = estimate_insert_cost(0, &leaves);
+        assert_eq!(
+            split_into_batches(0, &leaves, cu_budget, false).unwrap(),
+            vec![&leaves[..]]
+        );
+
+        // With a leaf already in the tree the budget covers two of them, and once they are
+        // in the third doesn't fit on its own
+        let err = split_into_batches(1, &leaves, cu_budget, false).unwrap_err();
+        assert!(err.to_string().starts_with("leaf 2 "));
+    }
+
+    #[test]
+    fn split_into_batches_stops_at_max_leaves() {
+        let leaves = vec!["a".to_string(), "b".to_string()];
+
+        assert!(split_into_batches(MAX_LEAVES - 2, &leaves, u64::MAX, false).is_ok());
+        assert!(split_into_batches(MAX_LEAVES - 1, &leaves, u64::MAX, false).is_err());
+    }
+}
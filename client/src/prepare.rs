@@ -1,47 +1,178 @@
-use anyhow::Result;
-use solana_client::rpc_client::RpcClient;
-use solana_sdk::{native_token::LAMPORTS_PER_SOL, signature::Keypair, signer::Signer};
+use std::path::Path;
+
+use anyhow::{Result, anyhow, bail};
+use solana_client::nonblocking::rpc_client::RpcClient;
+use solana_sdk::{
+    commitment_config::CommitmentConfig,
+    native_token::LAMPORTS_PER_SOL,
+    signature::Keypair,
+    signer::{EncodableKey, Signer},
+};
 use tokio::fs;
 
+use crate::{
+    airdrop::airdrop,
+    geyser,
+    retry::RetryPolicy,
+    transfer::WalletsPair,
+    util::{load_keypair, resolve_rpc_url},
+};
+
+// Airdropped to every generated wallet
+const WALLET_AIRDROP_LAMPORTS: u64 = LAMPORTS_PER_SOL / 1000;
+
+// Sent by the generated transfer and geyser configs, leaving plenty for the fees
+const TRANSFER_LAMPORTS: u64 = 1_000;
+
+#[derive(Clone, Copy, Debug)]
+pub struct PrepareOptions {
+    // Number of wallets to generate and fund
+    pub count: usize,
+    // Retries failed RPC requests and rate-limited airdrops
+    pub retry: RetryPolicy,
+}
+
 pub async fn prepare(
     balances_config: &str,
+    keypairs_file: &str,
     transfer_config: String,
     geyser_config: String,
+    split_dir: Option<&str>,
+    rpc_url: Option<&str>,
+    options: PrepareOptions,
 ) -> Result<()> {
-    prepare_balances_config(balances_config).await?;
-    prepare_transfer_config(transfer_config).await?;
-    prepare_geyser_config(geyser_config).await
+    prepare_balances_config(balances_config, keypairs_file, split_dir, rpc_url, options).await?;
+
+    // The configs are built from the wallets that have their secret saved
+    let keypairs: Vec<String> = serde_yaml::from_str(&fs::read_to_string(keypairs_file).await?)?;
+    let keypairs = keypairs
+        .iter()
+        .map(|secret| load_keypair(secret))
+        .collect::<Result<Vec<_>>>()?;
+    prepare_transfer_config(&transfer_config, &keypairs).await?;
+    prepare_geyser_config(&geyser_config, &keypairs).await
 }
 
-async fn prepare_balances_config(config_file: &str) -> Result<()> {
-    if fs::try_exists(config_file).await? {
-        println!("Balances file already exists. Delete it to regenerate.");
+// Funds new wallets, listing their pubkeys in `config_file` and their base58 secrets in
+// `keypairs_file`, so they can be used as transfer sources later on
+async fn prepare_balances_config(
+    config_file: &str,
+    keypairs_file: &str,
+    split_dir: Option<&str>,
+    rpc_url: Option<&str>,
+    options: PrepareOptions,
+) -> Result<()> {
+    let PrepareOptions {
+        count: wallets_count,
+        retry,
+    } = options;
+
+    // Resume from wallets funded by a previous, possibly interrupted, run
+    let mut wallets: Vec<String> = if fs::try_exists(config_file).await? {
+        serde_yaml::from_str(&fs::read_to_string(config_file).await?)?
+    } else {
+        Vec::new()
+    };
+    if wallets.len() >= wallets_count {
+        println!("Balances file already contains {} wallets.", wallets.len());
         return Ok(());
     }
+    if !wallets.is_empty() {
+        println!("Resuming from wallet {}/{wallets_count}", wallets.len());
+    }
+    let mut keypairs: Vec<String> = if fs::try_exists(keypairs_file).await? {
+        serde_yaml::from_str(&fs::read_to_string(keypairs_file).await?)?
+    } else {
+        Vec::new()
+    };
+    if keypairs.len() < wallets.len() {
+        println!(
+            "{} wallets from an earlier run have no saved keypair",
+            wallets.len() - keypairs.len()
+        );
+    }
 
-    let rpc_url = dotenv::var("RPC_URL").expect("Missing solana rpc url");
-    let rpc_client = RpcClient::new(rpc_url);
-
-    let wallets: Vec<String> = (0..500)
-        .map(|i| {
-            let keypair = Keypair::new();
-            rpc_client
-                .request_airdrop(&keypair.pubkey(), LAMPORTS_PER_SOL / (1000 - i))
-                .expect("failed to request airdrop");
-            println!("Wallet {i}/500 funded");
-            keypair.pubkey().to_string()
-        })
-        .collect();
-    let output = serde_yaml::to_string(&wallets)?;
-    fs::write(config_file, output).await?;
+    let rpc_url = resolve_rpc_url(rpc_url)?;
+    let rpc_client = RpcClient::new_with_commitment(rpc_url, CommitmentConfig::confirmed());
+
+    if let Some(dir) = split_dir {
+        fs::create_dir_all(dir).await?;
+    }
+
+    for i in wallets.len()..wallets_count {
+        let keypair = Keypair::new();
+        // Only a wallet whose airdrop landed counts as funded, otherwise a resumed run
+        // would skip it
+        airdrop(
+            &rpc_client,
+            &keypair.pubkey(),
+            WALLET_AIRDROP_LAMPORTS,
+            retry,
+        )
+        .await?;
+        let balance = rpc_client.get_balance(&keypair.pubkey()).await?;
+        if balance < WALLET_AIRDROP_LAMPORTS {
+            bail!(
+                "airdrop to {} landed but the wallet holds only {balance} lamports",
+                keypair.pubkey()
+            );
+        }
+        wallets.push(keypair.pubkey().to_string());
+
+        // Same layout as `solana-keygen new -o <pubkey>.json`
+        if let Some(dir) = split_dir {
+            let keypair_file = Path::new(dir).join(format!("{}.json", keypair.pubkey()));
+            keypair
+                .write_to_file(&keypair_file)
+                .map_err(|e| anyhow!("failed to write {}: {e}", keypair_file.display()))?;
+        }
+
+        // Checkpoint after every funded wallet so a rerun only tops up the remainder. The
+        // secret goes first, so no listed wallet is ever left without it.
+        keypairs.push(keypair.to_base58_string());
+        write_checkpoint(keypairs_file, &keypairs).await?;
+        write_checkpoint(config_file, &wallets).await?;
+        println!("Wallet {}/{wallets_count} funded", i + 1);
+    }
 
     Ok(())
 }
 
-async fn prepare_geyser_config(config_file: String) -> Result<()> {
-    todo!()
+// Writes through a temporary file so an interrupt never leaves a truncated config behind.
+async fn write_checkpoint(config_file: &str, wallets: &[String]) -> Result<()> {
+    let tmp_file = format!("{config_file}.tmp");
+    fs::write(&tmp_file, serde_yaml::to_string(wallets)?).await?;
+    fs::rename(&tmp_file, config_file).await?;
+    Ok(())
+}
+
+// Every wallet sends to the next one and the last one to the first, so a transfer run
+// only needs the wallets `prepare` funded
+async fn prepare_transfer_config(config_file: &str, keypairs: &[Keypair]) -> Result<()> {
+    let recipients = keypairs.iter().map(Keypair::pubkey).cycle().skip(1);
+    let transfers: Vec<WalletsPair> = keypairs
+        .iter()
+        .zip(recipients)
+        .map(|(keypair, to)| WalletsPair::new(keypair.to_base58_string(), to, TRANSFER_LAMPORTS))
+        .collect();
+    fs::write(config_file, serde_yaml::to_string(&transfers)?).await?;
+    println!("Wrote {} transfers to {config_file}", transfers.len());
+    Ok(())
 }
 
-async fn prepare_transfer_config(config_file: String) -> Result<()> {
-    todo!()
+// The first wallet sends to the second, or to itself when it's the only one
+async fn prepare_geyser_config(config_file: &str, keypairs: &[Keypair]) -> Result<()> {
+    let (sender, recipient) = match keypairs {
+        [sender, recipient, ..] => (sender, recipient),
+        [sender] => (sender, sender),
+        [] => bail!("no wallet keypairs to write {config_file} from"),
+    };
+    let config = geyser::Config::new(
+        sender.to_base58_string(),
+        recipient.pubkey(),
+        TRANSFER_LAMPORTS as f64 / LAMPORTS_PER_SOL as f64,
+    );
+    fs::write(config_file, serde_yaml::to_string(&config)?).await?;
+    println!("Wrote geyser config to {config_file}");
+    Ok(())
 }
@@ -0,0 +1,75 @@
+use std::str::FromStr;
+
+use anyhow::{Result, anyhow, bail};
+use solana_client::nonblocking::rpc_client::RpcClient;
+use solana_sdk::pubkey::Pubkey;
+
+// Percentile used by a bare `auto`
+const DEFAULT_PERCENTILE: u8 = 75;
+
+// Maximum number of accounts `getRecentPrioritizationFees` accepts
+pub const MAX_FEE_ACCOUNTS: usize = 128;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PriorityFee {
+    // Fixed compute unit price in micro-lamports
+    Fixed(u64),
+    // Percentile of the prioritization fees recently paid for the same accounts
+    Auto { percentile: u8 },
+}
+
+impl FromStr for PriorityFee {
+    type Err = anyhow::Error;
+
+    // Accepts `<micro-lamports>`, `auto` or `auto:p<percentile>`
+    fn from_str(s: &str) -> Result<Self> {
+        let Some(auto) = s.strip_prefix("auto") else {
+            return Ok(Self::Fixed(s.parse().map_err(|_| {
+                anyhow!("invalid priority `{s}`, expected micro-lamports or auto:p<percentile>")
+            })?));
+        };
+
+        let percentile = match auto.strip_prefix(":p") {
+            Some(percentile) => percentile
+                .parse()
+                .map_err(|_| anyhow!("invalid priority percentile `{percentile}`"))?,
+            None if auto.is_empty() => DEFAULT_PERCENTILE,
+            None => bail!("invalid priority `{s}`, expected auto:p<percentile>"),
+        };
+        if percentile > 100 {
+            bail!("priority percentile must be at most 100");
+        }
+        Ok(Self::Auto { percentile })
+    }
+}
+
+impl PriorityFee {
+    pub async fn compute_unit_price(
+        self,
+        rpc_client: &RpcClient,
+        accounts: &[Pubkey],
+    ) -> Result<u64> {
+        match self {
+            Self::Fixed(price) => Ok(price),
+            Self::Auto { percentile } => {
+                let fees = rpc_client
+                    .get_recent_prioritization_fees(accounts)
+                    .await?
+                    .into_iter()
+                    .map(|fee| fee.prioritization_fee)
+                    .collect();
+                Ok(fee_percentile(fees, percentile))
+            }
+        }
+    }
+}
+
+// Nearest-rank percentile, zero when no fees were reported
+fn fee_percentile(mut fees: Vec<u64>, percentile: u8) -> u64 {
+    if fees.is_empty() {
+        return 0;
+    }
+    fees.sort_unstable();
+    let rank = (fees.len() * percentile as usize).div_ceil(100).max(1);
+    fees[rank - 1]
+}
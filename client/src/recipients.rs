@@ -0,0 +1,46 @@
+use std::str::FromStr;
+
+use anyhow::{Result, anyhow};
+use solana_sdk::{keccak::hashv, pubkey::Pubkey};
+
+// A reproducible set of recipients, so load test targets never have to be stored
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SeededRecipients {
+    pub seed: String,
+    pub count: usize,
+}
+
+impl FromStr for SeededRecipients {
+    type Err = anyhow::Error;
+
+    // Accepts `seed:<STR>:<COUNT>`
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || anyhow!("invalid recipients `{s}`, expected seed:<STR>:<COUNT>");
+        let (seed, count) = s
+            .strip_prefix("seed:")
+            .and_then(|rest| rest.rsplit_once(':'))
+            .ok_or_else(invalid)?;
+        let count = count.parse().map_err(|_| invalid())?;
+        if seed.is_empty() || count == 0 {
+            return Err(invalid());
+        }
+        Ok(Self {
+            seed: seed.to_string(),
+            count,
+        })
+    }
+}
+
+impl SeededRecipients {
+    pub fn pubkeys(&self) -> Vec<Pubkey> {
+        (0..self.count as u64)
+            .map(|index| recipient_pubkey(&self.seed, index))
+            .collect()
+    }
+}
+
+// Hashing keeps the derivation independent of any program or base key;
+// nobody holds the private keys, which is fine for recipients.
+pub fn recipient_pubkey(seed: &str, index: u64) -> Pubkey {
+    Pubkey::new_from_array(hashv(&[b"recipient", seed.as_bytes(), &index.to_le_bytes()]).to_bytes())
+}
@@ -0,0 +1,55 @@
+use anyhow::{Result, bail};
+use clap::ValueEnum;
+use program::{
+    mtree::{MAX_LEAVES, MerkleTree},
+    state::DepositAccount,
+};
+use solana_client::nonblocking::rpc_client::RpcClient;
+
+use crate::util::{format_sol, resolve_rpc_url};
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum AccountKind {
+    Deposit,
+    Merkle,
+}
+
+// Prints the rent-exempt minimum for `count` accounts of the given size
+pub async fn rent_estimate(
+    bytes: Option<usize>,
+    kind: Option<AccountKind>,
+    leaves: usize,
+    count: u64,
+    rpc_url: Option<&str>,
+) -> Result<()> {
+    let bytes = match (bytes, kind) {
+        (Some(bytes), None) => bytes,
+        (None, Some(AccountKind::Deposit)) => DepositAccount::LEN,
+        (None, Some(AccountKind::Merkle)) if leaves > MAX_LEAVES => {
+            bail!("a merkle account holds at most {MAX_LEAVES} leaves")
+        }
+        (None, Some(AccountKind::Merkle)) => MerkleTree::space(leaves),
+        _ => bail!("pass exactly one of --bytes or --for"),
+    };
+
+    // Connect to Solana network
+    let rpc_url = resolve_rpc_url(rpc_url)?;
+    let rpc_client = RpcClient::new(rpc_url);
+
+    let lamports = rpc_client
+        .get_minimum_balance_for_rent_exemption(bytes)
+        .await?;
+    println!(
+        "Rent-exempt minimum for {bytes} bytes: {lamports} lamports ({} SOL)",
+        format_sol(lamports)
+    );
+    if count > 1 {
+        let total = lamports * count;
+        println!(
+            "Total for {count} accounts: {total} lamports ({} SOL)",
+            format_sol(total)
+        );
+    }
+
+    Ok(())
+}
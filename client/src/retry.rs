@@ -0,0 +1,95 @@
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    time::Duration,
+};
+
+use clap::Args;
+use solana_client::client_error::{ClientError, ClientErrorKind};
+use tokio::time::sleep;
+
+// How failed RPC requests are retried, shared by every command
+#[derive(Clone, Copy, Debug, Args)]
+pub struct RetryPolicy {
+    /// Maximum attempts per request, including the first one.
+    #[clap(long = "retries", global = true, default_value_t = 3)]
+    pub max_attempts: u32,
+    /// Delay before the first retry, doubled on every following one.
+    #[clap(long = "retry-base-ms", global = true, default_value_t = 500)]
+    pub base_delay_ms: u64,
+    /// Upper bound for the delay between retries.
+    #[clap(long = "retry-max-ms", global = true, default_value_t = 10_000)]
+    pub max_delay_ms: u64,
+    /// Pick every delay at random up to its computed value, to spread out concurrent retries.
+    #[clap(long = "retry-jitter", global = true)]
+    pub jitter: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            base_delay_ms: 500,
+            max_delay_ms: 10_000,
+            jitter: false,
+        }
+    }
+}
+
+impl RetryPolicy {
+    // Delay after the given failed attempt, counting from 1
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let delay_ms = self
+            .base_delay_ms
+            .saturating_mul(1 << (attempt - 1).min(32))
+            .min(self.max_delay_ms);
+        if !self.jitter || delay_ms == 0 {
+            return Duration::from_millis(delay_ms);
+        }
+        // A freshly seeded hasher is random enough to spread retries apart
+        let random = RandomState::new().build_hasher().finish();
+        Duration::from_millis(random % (delay_ms + 1))
+    }
+
+    // Runs `f` until it succeeds, fails with an error `retryable` rejects, or runs out of attempts
+    pub async fn run<T, E, F, Fut>(&self, mut f: F, retryable: impl Fn(&E) -> bool) -> Result<T, E>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+    {
+        let mut attempt = 1;
+        loop {
+            match f().await {
+                Err(err) if attempt < self.max_attempts && retryable(&err) => {
+                    sleep(self.delay(attempt)).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+// Network failures and overloaded nodes, as opposed to errors a retry can't fix
+pub fn is_transient(err: &ClientError) -> bool {
+    match err.kind() {
+        ClientErrorKind::Io(_) => true,
+        ClientErrorKind::Reqwest(err) => {
+            err.is_timeout()
+                || err.is_connect()
+                || err
+                    .status()
+                    .is_some_and(|status| status.as_u16() == 429 || status.is_server_error())
+        }
+        _ => false,
+    }
+}
+
+// Same as `is_transient`, for requests sent over HTTP directly instead of the RPC client
+pub fn is_transient_http(err: &reqwest::Error) -> bool {
+    err.is_timeout()
+        || err.is_connect()
+        || err
+            .status()
+            .is_some_and(|status| status.as_u16() == 429 || status.is_server_error())
+}
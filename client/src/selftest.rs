@@ -0,0 +1,135 @@
+use std::str::FromStr;
+
+use anyhow::{Result, bail};
+use serde::{Deserialize, Serialize};
+use solana_client::nonblocking::rpc_client::RpcClient;
+use solana_sdk::{
+    commitment_config::CommitmentConfig,
+    pubkey::Pubkey,
+    signature::{Keypair, Signer},
+};
+
+use crate::{
+    config::load_config,
+    depository::{open_deposit, stored_balance},
+    instructions::withdraw_ix,
+    retry::RetryPolicy,
+    send::{SendOptions, send_resilient},
+    util::{resolve_rpc_url, sol_to_lamports},
+};
+
+// Scenario amounts and the outcomes the run is checked against, all in SOL
+#[derive(Debug, Serialize, Deserialize)]
+struct Config {
+    airdrop_sol: f64,
+    deposit_sol: f64,
+    withdraw_sol: f64,
+    expected_wallet_sol: f64,
+    expected_stored_after_deposit_sol: f64,
+    expected_stored_after_withdraw_sol: f64,
+}
+
+// Outcome of a single scenario step
+struct Check {
+    step: &'static str,
+    expected: u64,
+    observed: u64,
+}
+
+// Runs a scripted wallet -> airdrop -> deposit -> withdraw scenario and fails
+// if anything observed along the way differs from the configured expectations.
+pub async fn self_test(
+    program_id: String,
+    file: String,
+    rpc_url: Option<&str>,
+    retry: RetryPolicy,
+) -> Result<()> {
+    let program_id = Pubkey::from_str(&program_id)?;
+    let config: Config = load_config(&file).await?;
+
+    // Connect to the cluster
+    let rpc_url = resolve_rpc_url(rpc_url)?;
+    let client = RpcClient::new_with_commitment(rpc_url, CommitmentConfig::confirmed());
+
+    let mut checks = Vec::new();
+
+    println!("Generating wallet...");
+    let payer = Keypair::new();
+    println!("Wallet: {}", payer.pubkey());
+
+    println!("Requesting airdrop of {} SOL...", config.airdrop_sol);
+    let airdrop = client
+        .request_airdrop(&payer.pubkey(), sol_to_lamports(config.airdrop_sol)?)
+        .await?;
+    client.poll_for_signature(&airdrop).await?;
+    checks.push(Check {
+        step: "wallet balance after airdrop",
+        expected: sol_to_lamports(config.expected_wallet_sol)?,
+        observed: client.get_balance(&payer.pubkey()).await?,
+    });
+
+    println!("Depositing {} SOL...", config.deposit_sol);
+    let pda = open_deposit(
+        &client,
+        &program_id,
+        &payer,
+        sol_to_lamports(config.deposit_sol)?,
+        retry,
+    )
+    .await?;
+    checks.push(Check {
+        step: "stored balance after deposit",
+        expected: sol_to_lamports(config.expected_stored_after_deposit_sol)?,
+        observed: stored_balance(&client, &pda).await?,
+    });
+
+    println!("Withdrawing {} SOL...", config.withdraw_sol);
+    send_resilient(
+        &client,
+        &[withdraw_ix(
+            &program_id,
+            &payer.pubkey(),
+            &pda,
+            sol_to_lamports(config.withdraw_sol)?,
+        )],
+        &[&payer],
+        &payer.pubkey(),
+        SendOptions {
+            retry,
+            ..SendOptions::default()
+        },
+    )
+    .await?
+    .confirmed()?;
+    checks.push(Check {
+        step: "stored balance after withdraw",
+        expected: sol_to_lamports(config.expected_stored_after_withdraw_sol)?,
+        observed: stored_balance(&client, &pda).await?,
+    });
+
+    println!(
+        "\n{:<36} {:>20} {:>20} {:>6}",
+        "Step", "Expected", "Observed", ""
+    );
+    println!("{}", "-".repeat(85));
+    let mut failed = 0;
+    for check in &checks {
+        let passed = check.expected == check.observed;
+        if !passed {
+            failed += 1;
+        }
+        println!(
+            "{:<36} {:>20} {:>20} {:>6}",
+            check.step,
+            check.expected,
+            check.observed,
+            if passed { "ok" } else { "FAIL" }
+        );
+    }
+
+    if failed > 0 {
+        bail!("{failed} of {} self-test checks failed", checks.len());
+    }
+    println!("\nAll {} self-test checks passed", checks.len());
+    Ok(())
+}
@@ -0,0 +1,482 @@
+use std::{collections::HashSet, time::Duration};
+
+use anyhow::{Result, bail};
+use serde::{Deserialize, Serialize};
+use solana_client::{nonblocking::rpc_client::RpcClient, rpc_config::RpcSendTransactionConfig};
+#[allow(deprecated)]
+use solana_sdk::system_instruction;
+use solana_sdk::{
+    commitment_config::CommitmentConfig,
+    compute_budget::ComputeBudgetInstruction,
+    hash::Hash,
+    instruction::Instruction,
+    message::Message,
+    packet::PACKET_DATA_SIZE,
+    pubkey::Pubkey,
+    signature::Signature,
+    signers::Signers,
+    transaction::{Transaction, TransactionError},
+};
+use solana_transaction_status_client_types::TransactionStatus;
+use tokio::time::{Instant, sleep};
+
+use crate::{
+    priority::{MAX_FEE_ACCOUNTS, PriorityFee},
+    retry::{RetryPolicy, is_transient},
+};
+
+const CONFIRM_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+// Fee parameters of the runtime, used to estimate what a transaction is charged
+const LAMPORTS_PER_SIGNATURE: u64 = 5_000;
+pub const DEFAULT_INSTRUCTION_COMPUTE_UNIT_LIMIT: u64 = 200_000;
+const MAX_COMPUTE_UNIT_LIMIT: u64 = 1_400_000;
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TxStatus {
+    Confirmed,
+    Failed(TransactionError),
+    Timeout,
+    // Last status seen while polling, when it never reached the requested commitment
+    Pending,
+    // The blockhash expired before the transaction landed, so it can be retried with a fresh one
+    Expired,
+    // Never made it to the cluster, e.g. the signer couldn't be loaded or the RPC kept failing
+    Error(String),
+}
+
+impl TxStatus {
+    pub const LEGEND: &str =
+        "0 - confirmed, 1 - failed, 2 - timeout, 3 - pending, 4 - expired, 5 - error";
+
+    fn from_signature_status(
+        status: Option<&TransactionStatus>,
+        commitment_config: CommitmentConfig,
+    ) -> Self {
+        match status {
+            Some(TransactionStatus { err: Some(err), .. }) => Self::Failed(err.clone()),
+            Some(status) if status.satisfies_commitment(commitment_config) => Self::Confirmed,
+            _ => Self::Pending,
+        }
+    }
+
+    // Stable machine-readable code, see `LEGEND`
+    pub fn code(&self) -> u8 {
+        match self {
+            Self::Confirmed => 0,
+            Self::Failed(_) => 1,
+            Self::Timeout => 2,
+            Self::Pending => 3,
+            Self::Expired => 4,
+            Self::Error(_) => 5,
+        }
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            Self::Confirmed => "confirmed",
+            Self::Failed(_) => "failed",
+            Self::Timeout => "timeout",
+            Self::Pending => "pending",
+            Self::Expired => "expired",
+            Self::Error(_) => "error",
+        }
+    }
+}
+
+// Nonce account whose stored blockhash signs the transaction instead of a recent one, so
+// the transaction doesn't expire
+#[derive(Clone, Copy, Debug)]
+pub struct DurableNonce {
+    pub account: Pubkey,
+    pub authority: Pubkey,
+    pub blockhash: Hash,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct SendOptions {
+    // Compute unit price to attach to the transaction
+    pub priority: Option<PriorityFee>,
+    pub compute_unit_limit: Option<u32>,
+    // Retries failed RPC requests, and bounds how often an expired transaction is resent
+    pub retry: RetryPolicy,
+    // Time budget for confirming, shared by all attempts
+    pub timeout: Duration,
+    pub commitment: CommitmentConfig,
+    // Wait for a blockhash newer than this one, so resending identical instructions
+    // can't produce a duplicate transaction
+    pub after_blockhash: Option<Hash>,
+    // Sign the first attempt with this blockhash instead of fetching one, retries still
+    // fetch a fresh one
+    pub blockhash: Option<Hash>,
+    // Takes precedence over `blockhash`, the nonce must be signed for by its authority
+    pub nonce: Option<DurableNonce>,
+    // Submit without simulating first, failures then only show up while confirming
+    pub skip_preflight: bool,
+}
+
+impl Default for SendOptions {
+    fn default() -> Self {
+        Self {
+            priority: None,
+            compute_unit_limit: None,
+            retry: RetryPolicy::default(),
+            timeout: Duration::from_secs(30),
+            commitment: CommitmentConfig::confirmed(),
+            after_blockhash: None,
+            blockhash: None,
+            nonce: None,
+            skip_preflight: false,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct SendOutcome {
+    // Signature of the last attempt
+    pub signature: Signature,
+    pub status: TxStatus,
+    // Last status observed on chain
+    pub signature_status: Option<TransactionStatus>,
+    pub blockhash: Hash,
+    pub attempts: u32,
+    // Time spent fetching blockhashes, submitting and confirming, summed over attempts
+    pub blockhash_time: Duration,
+    pub send_time: Duration,
+    pub confirm_time: Duration,
+    // Lamports charged once the transaction lands, whether it succeeds or not
+    pub fee: u64,
+}
+
+impl SendOutcome {
+    // Signature of a confirmed transaction, or an error explaining why it didn't confirm
+    pub fn confirmed(self) -> Result<Signature> {
+        match self.status {
+            TxStatus::Confirmed => Ok(self.signature),
+            TxStatus::Failed(err) => {
+                Err(anyhow::Error::new(err)
+                    .context(format!("transaction {} failed", self.signature)))
+            }
+            status => bail!(
+                "transaction {} not confirmed after {} attempts: {}",
+                self.signature,
+                self.attempts,
+                status.label()
+            ),
+        }
+    }
+}
+
+// Compute budget instructions for an already resolved price and limit
+pub fn compute_budget_instructions(
+    compute_unit_price: Option<u64>,
+    compute_unit_limit: Option<u32>,
+) -> Vec<Instruction> {
+    let mut instructions = Vec::new();
+    if let Some(limit) = compute_unit_limit {
+        instructions.push(ComputeBudgetInstruction::set_compute_unit_limit(limit));
+    }
+    if let Some(price) = compute_unit_price {
+        instructions.push(ComputeBudgetInstruction::set_compute_unit_price(price));
+    }
+    instructions
+}
+
+// Base fee for the signatures plus the priority fee on the requested compute units, used
+// when the cluster can't price the message itself. `instruction_count` excludes compute
+// budget instructions, which get no default limit.
+pub fn estimate_fee(
+    message: &Message,
+    instruction_count: usize,
+    compute_unit_price: Option<u64>,
+    compute_unit_limit: Option<u32>,
+) -> u64 {
+    let base_fee = LAMPORTS_PER_SIGNATURE * message.header.num_required_signatures as u64;
+    let compute_unit_limit = match compute_unit_limit {
+        Some(limit) => limit as u64,
+        None => DEFAULT_INSTRUCTION_COMPUTE_UNIT_LIMIT * instruction_count as u64,
+    }
+    .min(MAX_COMPUTE_UNIT_LIMIT);
+    // The price is in micro-lamports per compute unit, rounded up to whole lamports
+    let priority_fee = (compute_unit_price.unwrap_or(0) as u128 * compute_unit_limit as u128)
+        .div_ceil(1_000_000) as u64;
+    base_fee + priority_fee
+}
+
+// Prices the transaction and checks it fits in a packet, returning the message to sign
+// along with its locally estimated fee
+async fn build_message(
+    rpc_client: &RpcClient,
+    instructions: &[Instruction],
+    payer: &Pubkey,
+    opts: SendOptions,
+) -> Result<(Message, u64)> {
+    let compute_unit_price = match opts.priority {
+        Some(priority) => {
+            // Price by the fees recently paid for the accounts this transaction writes
+            let accounts: HashSet<Pubkey> = instructions
+                .iter()
+                .flat_map(|instruction| &instruction.accounts)
+                .filter(|meta| meta.is_writable)
+                .map(|meta| meta.pubkey)
+                .chain([*payer])
+                .collect();
+            let accounts: Vec<Pubkey> = accounts.into_iter().take(MAX_FEE_ACCOUNTS).collect();
+            Some(priority.compute_unit_price(rpc_client, &accounts).await?)
+        }
+        None => None,
+    };
+    // Advancing the nonce has to be the first instruction
+    let mut all_instructions: Vec<Instruction> = opts
+        .nonce
+        .iter()
+        .map(|nonce| system_instruction::advance_nonce_account(&nonce.account, &nonce.authority))
+        .collect();
+    let nonce_instructions = all_instructions.len();
+    all_instructions.extend(compute_budget_instructions(
+        compute_unit_price,
+        opts.compute_unit_limit,
+    ));
+    all_instructions.extend_from_slice(instructions);
+
+    let message = Message::new(&all_instructions, Some(payer));
+    // Signature count prefix, signatures and the message itself
+    let size = 1 + 64 * message.header.num_required_signatures as usize + message.serialize().len();
+    if size > PACKET_DATA_SIZE {
+        bail!("transaction is {size} bytes, over the {PACKET_DATA_SIZE} bytes limit");
+    }
+    let fee = estimate_fee(
+        &message,
+        nonce_instructions + instructions.len(),
+        compute_unit_price,
+        opts.compute_unit_limit,
+    );
+    Ok((message, fee))
+}
+
+// Fee the cluster charges for `message` signed with `blockhash`, falling back to the
+// local estimate when the RPC node can't price it
+pub async fn fee_for_message(
+    rpc_client: &RpcClient,
+    message: &Message,
+    blockhash: Hash,
+    estimate: u64,
+) -> u64 {
+    let mut message = message.clone();
+    message.recent_blockhash = blockhash;
+    rpc_client
+        .get_fee_for_message(&message)
+        .await
+        .unwrap_or(estimate)
+}
+
+// Sends `instructions` as one transaction with the requested priority and waits for it
+// to confirm. Attempts whose blockhash expired are resent with a fresh one, since an
+// expired transaction can no longer land.
+pub async fn send_resilient<T: Signers + ?Sized>(
+    rpc_client: &RpcClient,
+    instructions: &[Instruction],
+    signers: &T,
+    payer: &Pubkey,
+    opts: SendOptions,
+) -> Result<SendOutcome> {
+    let (message, estimated_fee) = build_message(rpc_client, instructions, payer, opts).await?;
+
+    let deadline = Instant::now() + opts.timeout;
+    let mut previous_blockhash = opts.after_blockhash;
+    let mut attempts: u32 = 0;
+    let mut blockhash_time = Duration::ZERO;
+    let mut send_time = Duration::ZERO;
+    let mut confirm_time = Duration::ZERO;
+    let mut fee = estimated_fee;
+    loop {
+        attempts += 1;
+
+        let start_time = Instant::now();
+        let blockhash = match (attempts, opts.nonce, opts.blockhash, previous_blockhash) {
+            (_, Some(nonce), _, _) => nonce.blockhash,
+            (1, None, Some(blockhash), _) => blockhash,
+            (_, None, _, Some(previous)) => {
+                opts.retry
+                    .run(
+                        || rpc_client.get_new_latest_blockhash(&previous),
+                        is_transient,
+                    )
+                    .await?
+            }
+            (_, None, _, None) => {
+                opts.retry
+                    .run(|| rpc_client.get_latest_blockhash(), is_transient)
+                    .await?
+            }
+        };
+        previous_blockhash = Some(blockhash);
+        blockhash_time += start_time.elapsed();
+        // The message stays the same across attempts, so pricing it once is enough
+        if attempts == 1 {
+            fee = fee_for_message(rpc_client, &message, blockhash, estimated_fee).await;
+        }
+
+        let mut transaction = Transaction::new_unsigned(message.clone());
+        transaction.try_sign(signers, blockhash)?;
+
+        let start_time = Instant::now();
+        // Resending the same signed transaction can't make it land twice
+        let config = RpcSendTransactionConfig {
+            skip_preflight: opts.skip_preflight,
+            preflight_commitment: Some(rpc_client.commitment().commitment),
+            ..RpcSendTransactionConfig::default()
+        };
+        let signature = opts
+            .retry
+            .run(
+                || rpc_client.send_transaction_with_config(&transaction, config),
+                is_transient,
+            )
+            .await?;
+        send_time += start_time.elapsed();
+
+        let start_time = Instant::now();
+        // A nonce stays valid until it is advanced, so only a recent blockhash can expire
+        let expiring_blockhash = opts.nonce.is_none().then_some(&blockhash);
+        let (status, signature_status) = confirm_transaction(
+            rpc_client,
+            &signature,
+            expiring_blockhash,
+            opts.commitment,
+            deadline,
+            opts.retry,
+        )
+        .await?;
+        confirm_time += start_time.elapsed();
+
+        if matches!(status, TxStatus::Expired)
+            && attempts < opts.retry.max_attempts
+            && Instant::now() < deadline
+        {
+            continue;
+        }
+        return Ok(SendOutcome {
+            signature,
+            status,
+            signature_status,
+            blockhash,
+            attempts,
+            blockhash_time,
+            send_time,
+            confirm_time,
+            fee,
+        });
+    }
+}
+
+// Builds the same transaction `send_resilient` would and simulates it instead, so nothing
+// changes on chain. Returns the outcome along with the program logs.
+pub async fn simulate_transaction<T: Signers + ?Sized>(
+    rpc_client: &RpcClient,
+    instructions: &[Instruction],
+    signers: &T,
+    payer: &Pubkey,
+    opts: SendOptions,
+) -> Result<(SendOutcome, Vec<String>)> {
+    let (message, estimated_fee) = build_message(rpc_client, instructions, payer, opts).await?;
+
+    let start_time = Instant::now();
+    let blockhash = match (opts.nonce, opts.blockhash) {
+        (Some(nonce), _) => nonce.blockhash,
+        (None, Some(blockhash)) => blockhash,
+        (None, None) => {
+            opts.retry
+                .run(|| rpc_client.get_latest_blockhash(), is_transient)
+                .await?
+        }
+    };
+    let blockhash_time = start_time.elapsed();
+    let fee = fee_for_message(rpc_client, &message, blockhash, estimated_fee).await;
+
+    let mut transaction = Transaction::new_unsigned(message);
+    transaction.try_sign(signers, blockhash)?;
+
+    let start_time = Instant::now();
+    let simulation = opts
+        .retry
+        .run(
+            || rpc_client.simulate_transaction(&transaction),
+            is_transient,
+        )
+        .await?
+        .value;
+    let status = match simulation.err {
+        Some(err) => TxStatus::Failed(err),
+        None => TxStatus::Confirmed,
+    };
+    let outcome = SendOutcome {
+        signature: transaction.signatures[0],
+        status,
+        signature_status: None,
+        blockhash,
+        attempts: 1,
+        blockhash_time,
+        send_time: start_time.elapsed(),
+        confirm_time: Duration::ZERO,
+        fee,
+    };
+    Ok((outcome, simulation.logs.unwrap_or_default()))
+}
+
+// Polls the signature status until the transaction reaches the requested commitment,
+// fails, its blockhash expires before it lands, or the deadline passes. Returns the last
+// status observed on chain as well. Without a blockhash only the deadline ends the wait.
+async fn confirm_transaction(
+    rpc_client: &RpcClient,
+    signature: &Signature,
+    blockhash: Option<&Hash>,
+    commitment_config: CommitmentConfig,
+    deadline: Instant,
+    retry: RetryPolicy,
+) -> Result<(TxStatus, Option<TransactionStatus>)> {
+    let signatures = [*signature];
+    let mut blockhash_expired = false;
+    loop {
+        let signature_status = retry
+            .run(
+                || rpc_client.get_signature_statuses(&signatures),
+                is_transient,
+            )
+            .await?
+            .value
+            .pop()
+            .flatten();
+        let status = TxStatus::from_signature_status(signature_status.as_ref(), commitment_config);
+        match status {
+            // A transaction seen on chain below the requested commitment has already landed,
+            // so it must not be resent even once its blockhash expires
+            TxStatus::Pending if blockhash_expired && signature_status.is_none() => {
+                return Ok((TxStatus::Expired, signature_status));
+            }
+            TxStatus::Pending if Instant::now() >= deadline => {
+                return Ok((TxStatus::Timeout, signature_status));
+            }
+            TxStatus::Pending => {
+                // The transaction may still land in the last block its blockhash is valid for,
+                // so an expired blockhash only counts after one more status check
+                if let (Some(blockhash), None) = (blockhash, &signature_status) {
+                    blockhash_expired = !retry
+                        .run(
+                            || {
+                                rpc_client
+                                    .is_blockhash_valid(blockhash, CommitmentConfig::processed())
+                            },
+                            is_transient,
+                        )
+                        .await?;
+                }
+                if !blockhash_expired || signature_status.is_some() {
+                    sleep(CONFIRM_POLL_INTERVAL).await;
+                }
+            }
+            status => return Ok((status, signature_status)),
+        }
+    }
+}
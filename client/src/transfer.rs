@@ -1,20 +1,136 @@
-use std::fs;
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    fs::{self, File},
+    io::Write,
+    str::FromStr,
+    time::Duration,
+};
 
-use anyhow::Result;
-use futures::{TryStreamExt, stream::FuturesUnordered};
+use anyhow::{Result, anyhow, bail};
+use clap::ValueEnum;
 use serde::{Deserialize, Serialize};
-use solana_client::nonblocking::rpc_client::RpcClient;
+use solana_client::{nonblocking::rpc_client::RpcClient, nonce_utils::nonblocking as nonce_utils};
+use solana_program_test::{BanksClient, ProgramTest, ProgramTestBanksClientExt};
+#[allow(deprecated)]
+use solana_sdk::system_instruction;
 use solana_sdk::{
-    commitment_config::CommitmentConfig, message::Message, pubkey::Pubkey, signature::Keypair,
-    signer::Signer, system_instruction, transaction::Transaction,
+    commitment_config::CommitmentConfig,
+    hash::Hash,
+    instruction::Instruction,
+    message::Message,
+    native_token::LAMPORTS_PER_SOL,
+    pubkey::Pubkey,
+    signature::Keypair,
+    signer::{EncodableKey, Signer},
+    transaction::Transaction,
+};
+use solana_transaction_status_client_types::TransactionConfirmationStatus;
+use tokio::{sync::Mutex, time::Instant};
+
+use crate::{
+    config::load_config_with,
+    priority::{MAX_FEE_ACCOUNTS, PriorityFee},
+    recipients::SeededRecipients,
+    retry::RetryPolicy,
+    send::{
+        DEFAULT_INSTRUCTION_COMPUTE_UNIT_LIMIT, DurableNonce, SendOptions, SendOutcome, TxStatus,
+        compute_budget_instructions, estimate_fee, send_resilient, simulate_transaction,
+    },
+    util::{
+        bounded_map, check_success_rate, format_sol, load_keypair, resolve_rpc_url, sol_to_lamports,
+    },
 };
-use tokio::time::Instant;
+
+// Default maximum number of transfers in flight at once
+pub const TRANSFER_CONCURRENCY: usize = 50;
+
+// SPL Memo program, its instructions carry the memo text as data
+const MEMO_PROGRAM_ID: Pubkey = solana_sdk::pubkey!("MemoSq4gqABAXKb96qnH8TysNcWxMyWCqXgDLGmfcHr");
+
+// Blockhashes stay valid for about a minute, a shared one is replaced well before that
+const BLOCKHASH_MAX_AGE: Duration = Duration::from_secs(30);
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct WalletsPair {
-    from_pk: String,
+    // Source keypair as base58, a JSON byte array or a keypair file path
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    from_pk: Option<String>,
+    // Source keypair file, keeps the secret itself out of the config
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    from_keypair_file: Option<String>,
     to: Pubkey,
-    amount_lamp: u64,
+    // Amount in lamports, or in SOL with `amount_sol`, exactly one of them must be set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    amount_lamp: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    amount_sol: Option<f64>,
+    // Durable nonce account to sign with instead of a recent blockhash. Transfers of
+    // different sources run concurrently, so they shouldn't share a nonce account.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    nonce_account: Option<Pubkey>,
+    // Keypair allowed to advance the nonce, the source when omitted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    nonce_authority: Option<String>,
+    // Attached as a memo instruction, so the transfer can be found on explorers
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    memo: Option<String>,
+}
+
+impl WalletsPair {
+    // Plain transfer from a base58 source keypair
+    pub fn new(from_pk: String, to: Pubkey, amount_lamp: u64) -> Self {
+        Self {
+            from_pk: Some(from_pk),
+            from_keypair_file: None,
+            to,
+            amount_lamp: Some(amount_lamp),
+            amount_sol: None,
+            nonce_account: None,
+            nonce_authority: None,
+            memo: None,
+        }
+    }
+
+    // Identifies the source, exactly one of `from_pk` and `from_keypair_file` must be set
+    fn source(&self) -> Result<&str> {
+        match (&self.from_pk, &self.from_keypair_file) {
+            (Some(from_pk), None) => Ok(from_pk),
+            (None, Some(path)) => Ok(path),
+            (Some(_), Some(_)) => bail!(
+                "transfer to {} sets both from_pk and from_keypair_file",
+                self.to
+            ),
+            (None, None) => bail!(
+                "transfer to {} sets neither from_pk nor from_keypair_file",
+                self.to
+            ),
+        }
+    }
+
+    fn amount(&self) -> Result<u64> {
+        match (self.amount_lamp, self.amount_sol) {
+            (Some(amount_lamp), None) => Ok(amount_lamp),
+            (None, Some(amount_sol)) => sol_to_lamports(amount_sol),
+            (Some(_), Some(_)) => bail!(
+                "transfer to {} sets both amount_lamp and amount_sol",
+                self.to
+            ),
+            (None, None) => bail!(
+                "transfer to {} sets neither amount_lamp nor amount_sol",
+                self.to
+            ),
+        }
+    }
+
+    fn source_keypair(&self) -> Result<Keypair> {
+        let source = self.source()?;
+        match self.from_keypair_file {
+            Some(_) => Keypair::read_from_file(source)
+                .map_err(|e| anyhow!("failed to read keypair file {source}: {e}")),
+            None => load_keypair(source),
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -22,107 +138,987 @@ struct TransferResult {
     signature: String,
     source: String,
     destination: String,
-    status: String,
+    // Stable code of `status`, see `TxStatus::LEGEND`
+    code: u8,
+    status: TxStatus,
     processing_time_ms: u64,
+    // Breakdown of `processing_time_ms`
+    blockhash_ms: u64,
+    send_ms: u64,
+    confirm_ms: u64,
+    // Estimated fee, only charged for transfers that landed
+    #[serde(default)]
+    fee_lamports: u64,
+    // Times the transaction was sent, it is resent when its blockhash expires
+    #[serde(default)]
+    attempts: u32,
+    // Program logs, only recorded in dry runs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    simulation_logs: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    memo: Option<String>,
+    // Only recorded in detailed mode
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    confirmation_status: Option<TransactionConfirmationStatus>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    confirmations: Option<usize>,
+}
+
+impl TransferResult {
+    // Records a transfer that failed before it could be sent, so the rest of the batch
+    // still gets reported
+    fn error(wallets: &WalletsPair, err: anyhow::Error, processing_time: Duration) -> Self {
+        let source = wallets
+            .source_keypair()
+            .map(|keypair| keypair.pubkey().to_string())
+            .unwrap_or_default();
+        let status = TxStatus::Error(format!("{err:#}"));
+        Self {
+            signature: String::new(),
+            source,
+            destination: wallets.to.to_string(),
+            code: status.code(),
+            status,
+            processing_time_ms: processing_time.as_millis() as u64,
+            blockhash_ms: 0,
+            send_ms: 0,
+            confirm_ms: 0,
+            fee_lamports: 0,
+            attempts: 0,
+            simulation_logs: None,
+            memo: wallets.memo.clone(),
+            confirmation_status: None,
+            confirmations: None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct TransferOptions {
+    // Record how deep each transfer confirmed
+    pub detailed: bool,
+    // Run against an in-process bank instead of a cluster
+    pub simulate_bank: bool,
+    // Simulate every transfer on the cluster instead of sending it
+    pub dry_run: bool,
+    // Submit transfers without a preflight simulation
+    pub skip_preflight: bool,
+    // Compute unit price to attach to every transfer
+    pub priority: Option<PriorityFee>,
+    pub compute_unit_limit: Option<u32>,
+    // Fetch a blockhash for every transfer instead of sharing one across the batch
+    pub fresh_blockhash_per_tx: bool,
+    // Maximum number of transfers in flight at once, transfers from one source never overlap
+    pub max_concurrency: usize,
+    // Reject unknown fields in the config instead of ignoring them
+    pub strict_config: bool,
+    pub retry: RetryPolicy,
+}
+
+impl Default for TransferOptions {
+    fn default() -> Self {
+        Self {
+            detailed: false,
+            simulate_bank: false,
+            dry_run: false,
+            skip_preflight: false,
+            priority: None,
+            compute_unit_limit: None,
+            fresh_blockhash_per_tx: false,
+            max_concurrency: TRANSFER_CONCURRENCY,
+            strict_config: false,
+            retry: RetryPolicy::default(),
+        }
+    }
+}
+
+// Serialization format of the results file
+#[derive(Clone, Copy, Debug, Default, ValueEnum)]
+pub enum ResultsFormat {
+    #[default]
+    Json,
+    // One row per transfer, nested fields are left out
+    Csv,
+}
+
+// Files a transfer run writes besides printing its results
+#[derive(Debug, Default)]
+pub struct TransferOutputs {
+    // Every result appended as a JSON line as soon as it completes
+    pub stream: Option<String>,
+    // Prometheus textfile metrics for the run
+    pub metrics_file: Option<String>,
+    // All results, written once the batch is done
+    pub output: Option<String>,
+    pub format: ResultsFormat,
+    // Config of the transfers that failed, to rerun just those
+    pub failures_out: Option<String>,
+}
+
+// Blockhash shared by a whole batch, refetched once it gets close to expiring
+#[derive(Default)]
+struct SharedBlockhash {
+    current: Mutex<Option<(Hash, Instant)>>,
+}
+
+impl SharedBlockhash {
+    // Cached blockhash, unless it is stale or equal to `avoid`
+    async fn get(&self, backend: &Backend, avoid: Option<Hash>) -> Result<Hash> {
+        let mut current = self.current.lock().await;
+        if let Some((blockhash, fetched_at)) = *current
+            && fetched_at.elapsed() < BLOCKHASH_MAX_AGE
+            && Some(blockhash) != avoid
+        {
+            return Ok(blockhash);
+        }
+        let blockhash = backend.latest_blockhash(avoid).await?;
+        *current = Some((blockhash, Instant::now()));
+        Ok(blockhash)
+    }
+}
+
+// Blockhash a source last sent with, and the transfers that went out under it
+#[derive(Default)]
+struct SourceState {
+    blockhash: Option<Hash>,
+    sent: HashSet<(Pubkey, u64)>,
+}
+
+// Where transactions are submitted and confirmed
+enum Backend {
+    Rpc(RpcClient),
+    Bank(BanksClient),
+}
+
+impl Backend {
+    // Starts an in-process bank and funds every source so the batch can run as on a cluster
+    async fn bank(wallets_pairs: &[WalletsPair]) -> Result<Self> {
+        let (banks_client, mint, _) = ProgramTest::default().start().await;
+
+        let mut required: HashMap<Pubkey, u64> = HashMap::new();
+        for wallets in wallets_pairs {
+            *required
+                .entry(wallets.source_keypair()?.pubkey())
+                .or_default() += wallets.amount()?;
+        }
+        for (source, amount) in required {
+            // Extra SOL covers fees and keeps the source rent-exempt
+            let instruction =
+                system_instruction::transfer(&mint.pubkey(), &source, amount + LAMPORTS_PER_SOL);
+            let transaction = Transaction::new_signed_with_payer(
+                &[instruction],
+                Some(&mint.pubkey()),
+                &[&mint],
+                banks_client.clone().get_latest_blockhash().await?,
+            );
+            banks_client
+                .clone()
+                .process_transaction(transaction)
+                .await?;
+        }
+        println!("Simulating transfers in an in-process bank");
+
+        Ok(Self::Bank(banks_client))
+    }
+
+    async fn compute_unit_price(&self, priority: PriorityFee, accounts: &[Pubkey]) -> Result<u64> {
+        match (self, priority) {
+            (Self::Rpc(rpc_client), priority) => {
+                priority.compute_unit_price(rpc_client, accounts).await
+            }
+            (Self::Bank(_), PriorityFee::Fixed(price)) => Ok(price),
+            // There is no fee market in the in-process bank
+            (Self::Bank(_), PriorityFee::Auto { .. }) => Ok(0),
+        }
+    }
+
+    // Latest blockhash, waiting for it to differ from `previous` when one is given
+    async fn latest_blockhash(&self, previous: Option<Hash>) -> Result<Hash> {
+        Ok(match (self, previous) {
+            (Self::Rpc(rpc_client), None) => rpc_client.get_latest_blockhash().await?,
+            (Self::Rpc(rpc_client), Some(previous)) => {
+                rpc_client.get_new_latest_blockhash(&previous).await?
+            }
+            (Self::Bank(banks_client), None) => banks_client.clone().get_latest_blockhash().await?,
+            (Self::Bank(banks_client), Some(previous)) => {
+                banks_client
+                    .clone()
+                    .get_new_latest_blockhash(&previous)
+                    .await?
+            }
+        })
+    }
+
+    // Blockhash currently stored in a durable nonce account
+    async fn nonce_blockhash(&self, nonce_account: &Pubkey) -> Result<Hash> {
+        match self {
+            Self::Rpc(rpc_client) => {
+                let account = nonce_utils::get_account_with_commitment(
+                    rpc_client,
+                    nonce_account,
+                    rpc_client.commitment(),
+                )
+                .await?;
+                Ok(nonce_utils::data_from_account(&account)?.blockhash())
+            }
+            Self::Bank(_) => bail!("durable nonces aren't supported by the in-process bank"),
+        }
+    }
+
+    // Simulates a transaction signed by `signers` without submitting it, the first one pays
+    async fn simulate(
+        &self,
+        instructions: &[Instruction],
+        signers: &[&Keypair],
+        opts: SendOptions,
+    ) -> Result<(SendOutcome, Vec<String>)> {
+        match self {
+            Self::Rpc(rpc_client) => {
+                let payer = signers[0].pubkey();
+                simulate_transaction(rpc_client, instructions, signers, &payer, opts).await
+            }
+            Self::Bank(_) => bail!("dry runs can't be combined with the in-process bank"),
+        }
+    }
+
+    // Submits a transaction signed by `signers` and waits for it to settle, the first one pays
+    async fn send(
+        &self,
+        instructions: &[Instruction],
+        signers: &[&Keypair],
+        opts: SendOptions,
+    ) -> Result<SendOutcome> {
+        let payer = signers[0].pubkey();
+        let banks_client = match self {
+            Self::Rpc(rpc_client) => {
+                return send_resilient(rpc_client, instructions, signers, &payer, opts).await;
+            }
+            Self::Bank(banks_client) => banks_client.clone(),
+        };
+        if opts.nonce.is_some() {
+            bail!("durable nonces aren't supported by the in-process bank");
+        }
+
+        let start_time = Instant::now();
+        let blockhash = match opts.blockhash {
+            Some(blockhash) => blockhash,
+            None => self.latest_blockhash(opts.after_blockhash).await?,
+        };
+        let blockhash_time = start_time.elapsed();
+
+        // Auto pricing was already resolved to zero for the bank
+        let compute_unit_price = opts.priority.map(|priority| match priority {
+            PriorityFee::Fixed(price) => price,
+            PriorityFee::Auto { .. } => 0,
+        });
+        let mut all_instructions =
+            compute_budget_instructions(compute_unit_price, opts.compute_unit_limit);
+        all_instructions.extend_from_slice(instructions);
+        let message = Message::new(&all_instructions, Some(&payer));
+        let estimated_fee = estimate_fee(
+            &message,
+            instructions.len(),
+            compute_unit_price,
+            opts.compute_unit_limit,
+        );
+        let transaction = Transaction::new(signers, message, blockhash);
+        let fee = banks_client
+            .get_fee_for_message(transaction.message.clone())
+            .await
+            .ok()
+            .flatten()
+            .unwrap_or(estimated_fee);
+
+        // The bank processes transactions synchronously, so there is nothing to poll
+        let start_time = Instant::now();
+        let processed = banks_client
+            .process_transaction_with_metadata(transaction.clone())
+            .await?;
+        let status = match processed.result {
+            Ok(()) => TxStatus::Confirmed,
+            Err(err) => TxStatus::Failed(err),
+        };
+        Ok(SendOutcome {
+            signature: transaction.signatures[0],
+            status,
+            signature_status: None,
+            blockhash,
+            attempts: 1,
+            blockhash_time,
+            send_time: start_time.elapsed(),
+            confirm_time: Duration::ZERO,
+            fee,
+        })
+    }
 }
 
-pub async fn transfer(file: String) -> Result<()> {
+pub async fn transfer(
+    file: String,
+    recipients: Option<SeededRecipients>,
+    outputs: TransferOutputs,
+    min_success_rate: Option<f64>,
+    rpc_url: Option<&str>,
+    options: TransferOptions,
+) -> Result<()> {
     // Read config file
-    let wallets: Vec<WalletsPair> = serde_yaml::from_str(&fs::read_to_string(file)?)?;
+    let mut wallets: Vec<WalletsPair> = load_config_with(&file, options.strict_config).await?;
+
+    // Spread the transfers round-robin over the seeded recipients instead of the configured ones
+    if let Some(recipients) = recipients {
+        let pubkeys = recipients.pubkeys();
+        for (wallets, to) in wallets.iter_mut().zip(pubkeys.iter().cycle()) {
+            wallets.to = *to;
+        }
+        println!(
+            "Sending to {} recipients derived from seed `{}`",
+            recipients.count, recipients.seed
+        );
+    }
+
+    // Perform transfers
+    let start_time = Instant::now();
+    let (wallets, results): (Vec<_>, Vec<_>) =
+        batch_transfer(wallets, outputs.stream.as_deref(), rpc_url, options)
+            .await?
+            .into_iter()
+            .unzip();
+    let batch_time = start_time.elapsed();
+
+    // Print results
+    print_transfer_results(&results);
+    // Lets runs with and without preflight checks be compared
+    println!(
+        "Throughput: {:.1} transfers/s ({})",
+        results.len() as f64 / batch_time.as_secs_f64(),
+        if options.skip_preflight {
+            "preflight skipped"
+        } else {
+            "with preflight"
+        }
+    );
+    if options.detailed {
+        print_confirmation_summary(&results);
+    }
+    if let Some(path) = &outputs.metrics_file {
+        write_metrics(path, &results)?;
+        println!("\nMetrics written to {path}");
+    }
+    if let Some(path) = &outputs.output {
+        write_results(path, &results, outputs.format)?;
+        println!("\nResults written to {path}");
+    }
+    if let Some(path) = &outputs.failures_out {
+        let retryable = write_failures(path, wallets, &results)?;
+        println!("\n{retryable} failed transfers written to {path}");
+    }
+
+    let succeeded = results
+        .iter()
+        .filter(|result| matches!(result.status, TxStatus::Confirmed))
+        .count();
+    check_success_rate(succeeded, results.len(), min_success_rate)
+}
+
+// Funds every wallet in the list from a single source, as an alternative to airdrops
+pub async fn distribute(
+    source_keypair: String,
+    wallets_file: String,
+    amount_sol: f64,
+    rpc_url: Option<&str>,
+    options: TransferOptions,
+) -> Result<()> {
+    let source =
+        load_keypair(&source_keypair).map_err(|e| anyhow!("failed to read source keypair: {e}"))?;
+    let wallets: Vec<String> = load_config_with(&wallets_file, options.strict_config).await?;
 
-    // Connect to Solana network
-    let rpc_url = dotenv::var("RPC_URL").expect("Missing solana rpc url");
+    let amount_lamp = sol_to_lamports(amount_sol)?;
+    let wallets_pairs = wallets
+        .iter()
+        .map(|wallet| {
+            Ok(WalletsPair {
+                from_pk: Some(source.to_base58_string()),
+                from_keypair_file: None,
+                to: Pubkey::from_str(wallet)?,
+                amount_lamp: Some(amount_lamp),
+                amount_sol: None,
+                nonce_account: None,
+                nonce_authority: None,
+                memo: None,
+            })
+        })
+        .collect::<Result<_>>()?;
+    println!(
+        "Distributing {} SOL from {} to each wallet in {wallets_file}",
+        format_sol(amount_lamp),
+        source.pubkey()
+    );
 
     // Perform transfers
-    let results = batch_transfer(wallets, rpc_url).await?;
+    let results: Vec<_> = batch_transfer(wallets_pairs, None, rpc_url, options)
+        .await?
+        .into_iter()
+        .map(|(_, result)| result)
+        .collect();
 
     // Print results
     print_transfer_results(&results);
+    if options.detailed {
+        print_confirmation_summary(&results);
+    }
 
     Ok(())
 }
 
+// Returns every transfer next to its result. When `stream` is set, every result is appended
+// to that file as a JSON line and printed as soon as its transfer completes, so partial
+// results survive an interrupted batch.
 async fn batch_transfer(
     wallets_pairs: Vec<WalletsPair>,
-    rpc_url: String,
-) -> Result<Vec<TransferResult>> {
+    stream: Option<&str>,
+    rpc_url: Option<&str>,
+    options: TransferOptions,
+) -> Result<Vec<(WalletsPair, TransferResult)>> {
+    if options.dry_run && options.simulate_bank {
+        bail!("--dry-run can't be combined with --simulate-bank");
+    }
+    if options.dry_run {
+        println!("Dry run, transfers are simulated and nothing is sent");
+    }
+
+    // Catch config mistakes before anything is sent
+    for wallets in &wallets_pairs {
+        wallets.source()?;
+        wallets.amount()?;
+        if options.simulate_bank && wallets.nonce_account.is_some() {
+            bail!("durable nonces can't be combined with --simulate-bank");
+        }
+    }
+
     let commitment_config = CommitmentConfig::confirmed();
-    let rpc_client = RpcClient::new_with_commitment(rpc_url, commitment_config);
+    let backend = if options.simulate_bank {
+        Backend::bank(&wallets_pairs).await?
+    } else {
+        // Connect to Solana network
+        let rpc_url = resolve_rpc_url(rpc_url)?;
+        Backend::Rpc(RpcClient::new_with_commitment(rpc_url, commitment_config))
+    };
+
+    // Price the whole batch once, based on the fees recently paid by its sources
+    let compute_unit_price = match options.priority {
+        Some(priority) => {
+            let sources: HashSet<Pubkey> = wallets_pairs
+                .iter()
+                .map(|wallets| Ok(wallets.source_keypair()?.pubkey()))
+                .collect::<Result<_>>()?;
+            let accounts: Vec<Pubkey> = sources.into_iter().take(MAX_FEE_ACCOUNTS).collect();
+            let price = backend.compute_unit_price(priority, &accounts).await?;
+            println!("Using compute unit price of {price} micro-lamports");
+            Some(price)
+        }
+        None => None,
+    };
+
+    let stream = match stream {
+        Some(path) => {
+            println!("Streaming results to {path}");
+            Some(File::create(path)?)
+        }
+        None => None,
+    };
+    let stream = RefCell::new(stream);
+
+    let shared_blockhash = SharedBlockhash::default();
 
-    let handlers = FuturesUnordered::new();
+    // Transfers sharing a source run one after another, and identical ones never share a
+    // blockhash, so they can't collide. Different sources still run concurrently.
+    let groups = group_by_source(wallets_pairs)?;
+    let results = bounded_map(groups, options.max_concurrency, |group| {
+        let backend = &backend;
+        let stream = &stream;
+        let shared_blockhash = &shared_blockhash;
+        async move {
+            let mut results = Vec::new();
+            let mut source_state = SourceState::default();
+            for wallets in group {
+                let start_time = Instant::now();
+                let result = single_transfer(
+                    commitment_config,
+                    backend,
+                    &wallets,
+                    (!options.fresh_blockhash_per_tx).then_some(shared_blockhash),
+                    &mut source_state,
+                    options,
+                    compute_unit_price,
+                )
+                .await
+                .unwrap_or_else(|err| TransferResult::error(&wallets, err, start_time.elapsed()));
+                if let Some(stream) = stream.borrow_mut().as_mut() {
+                    stream_result(stream, &result)?;
+                }
+                results.push((wallets, result));
+            }
+            Result::<_, anyhow::Error>::Ok(results)
+        }
+    })
+    .await?;
+
+    let mut transfers = Vec::new();
+    for group in results {
+        transfers.extend(group?);
+    }
+    Ok(transfers)
+}
+
+// Splits the batch into per-source groups, keeping each source's transfers in config order
+fn group_by_source(wallets_pairs: Vec<WalletsPair>) -> Result<Vec<Vec<WalletsPair>>> {
+    let mut groups: Vec<Vec<WalletsPair>> = Vec::new();
+    let mut group_of_source: HashMap<String, usize> = HashMap::new();
     for wallets in wallets_pairs {
-        handlers.push(single_transfer(commitment_config, &rpc_client, wallets));
+        let index = *group_of_source
+            .entry(wallets.source()?.to_string())
+            .or_insert_with(|| {
+                groups.push(Vec::new());
+                groups.len() - 1
+            });
+        groups[index].push(wallets);
+    }
+    Ok(groups)
+}
+
+fn stream_result(stream: &mut File, result: &TransferResult) -> Result<()> {
+    writeln!(stream, "{}", serde_json::to_string(result)?)?;
+    println!(
+        "[{}] {} {} -> {} ({} ms)",
+        result.status.label(),
+        result.signature,
+        result.source,
+        result.destination,
+        result.processing_time_ms
+    );
+    if let TxStatus::Error(err) = &result.status {
+        eprintln!("Transfer failed: {err}");
     }
-    let output = handlers.try_collect().await?;
-    Ok(output)
+    Ok(())
 }
 
 async fn single_transfer(
     commitment_config: CommitmentConfig,
-    rpc_client: &RpcClient,
-    wallets: WalletsPair,
+    backend: &Backend,
+    wallets: &WalletsPair,
+    shared_blockhash: Option<&SharedBlockhash>,
+    source_state: &mut SourceState,
+    options: TransferOptions,
+    compute_unit_price: Option<u64>,
 ) -> Result<TransferResult, anyhow::Error> {
-    let source_keypair = Keypair::from_base58_string(&wallets.from_pk);
-    let recent_blockhash = rpc_client.get_latest_blockhash().await?;
-    let instruction =
-        system_instruction::transfer(&source_keypair.pubkey(), &wallets.to, wallets.amount_lamp);
-    let message = Message::new(&[instruction], Some(&source_keypair.pubkey()));
-    let transaction = Transaction::new(&[&source_keypair], message, recent_blockhash);
-
-    // Send tx and measure completion time.
+    let source_keypair = wallets.source_keypair()?;
+    let nonce_authority = wallets
+        .nonce_authority
+        .as_deref()
+        .map(load_keypair)
+        .transpose()?;
+
+    // Only an identical transfer sent under the same blockhash would collide
+    let amount_lamp = wallets.amount()?;
+    let transfer_key = (wallets.to, amount_lamp);
+    let after_blockhash = match shared_blockhash {
+        Some(_) if !source_state.sent.contains(&transfer_key) => None,
+        _ => source_state.blockhash,
+    };
     let start_time = Instant::now();
-    let signature = rpc_client
-        .send_and_confirm_transaction(&transaction)
-        .await?;
-    let elapsed = start_time.elapsed().as_millis() as u64;
+    let blockhash = match shared_blockhash {
+        Some(shared_blockhash) if wallets.nonce_account.is_none() => {
+            Some(shared_blockhash.get(backend, after_blockhash).await?)
+        }
+        _ => None,
+    };
+    // The nonce stands in for the blockhash, the transfer advances it when it lands
+    let nonce = match wallets.nonce_account {
+        Some(account) => Some(DurableNonce {
+            account,
+            authority: nonce_authority.as_ref().unwrap_or(&source_keypair).pubkey(),
+            blockhash: backend.nonce_blockhash(&account).await?,
+        }),
+        None => None,
+    };
+    let shared_blockhash_time = start_time.elapsed();
 
-    let status = rpc_client
-        .get_signature_status_with_commitment(&signature, commitment_config)
-        .await?
-        .expect("assuming unreachable")
-        .map(|_| "success")
-        .unwrap_or("failure");
+    let mut signers = vec![&source_keypair];
+    signers.extend(&nonce_authority);
+
+    let mut instructions = vec![system_instruction::transfer(
+        &source_keypair.pubkey(),
+        &wallets.to,
+        amount_lamp,
+    )];
+    if let Some(memo) = &wallets.memo {
+        instructions.push(Instruction::new_with_bytes(
+            MEMO_PROGRAM_ID,
+            memo.as_bytes(),
+            Vec::new(),
+        ));
+    }
+    let opts = SendOptions {
+        priority: compute_unit_price.map(PriorityFee::Fixed),
+        compute_unit_limit: options.compute_unit_limit,
+        commitment: commitment_config,
+        after_blockhash,
+        blockhash,
+        nonce,
+        skip_preflight: options.skip_preflight,
+        retry: options.retry,
+        ..SendOptions::default()
+    };
+    let (outcome, simulation_logs) = if options.dry_run {
+        let (outcome, logs) = backend.simulate(&instructions, &signers, opts).await?;
+        (outcome, Some(logs))
+    } else {
+        let outcome = backend.send(&instructions, &signers, opts).await?;
+        if source_state.blockhash != Some(outcome.blockhash) {
+            source_state.blockhash = Some(outcome.blockhash);
+            source_state.sent.clear();
+        }
+        source_state.sent.insert(transfer_key);
+        (outcome, None)
+    };
+    let blockhash_ms = (shared_blockhash_time + outcome.blockhash_time).as_millis() as u64;
+    let send_ms = outcome.send_time.as_millis() as u64;
+    let confirm_ms = outcome.confirm_time.as_millis() as u64;
+
+    let (confirmation_status, confirmations) = match outcome.signature_status {
+        Some(signature_status) if options.detailed => (
+            signature_status.confirmation_status,
+            signature_status.confirmations,
+        ),
+        _ => (None, None),
+    };
     let result = TransferResult {
-        signature: signature.to_string(),
+        signature: outcome.signature.to_string(),
         source: source_keypair.pubkey().to_string(),
         destination: wallets.to.to_string(),
-        status: status.to_string(),
-        processing_time_ms: elapsed,
+        code: outcome.status.code(),
+        status: outcome.status,
+        processing_time_ms: blockhash_ms + send_ms + confirm_ms,
+        blockhash_ms,
+        send_ms,
+        confirm_ms,
+        fee_lamports: outcome.fee,
+        attempts: outcome.attempts,
+        simulation_logs,
+        memo: wallets.memo.clone(),
+        confirmation_status,
+        confirmations,
     };
     Ok(result)
 }
 
+// Fees are only charged for transactions that made it into a block
+fn landed_fees(results: &[TransferResult]) -> u64 {
+    results
+        .iter()
+        .filter(|result| matches!(result.status, TxStatus::Confirmed | TxStatus::Failed(_)))
+        .map(|result| result.fee_lamports)
+        .sum()
+}
+
+// Writes Prometheus text format metrics for a node_exporter textfile collector. The file is
+// replaced atomically, so the collector never scrapes a partially written one.
+fn write_metrics(path: &str, results: &[TransferResult]) -> Result<()> {
+    let failed = results
+        .iter()
+        .filter(|result| !matches!(result.status, TxStatus::Confirmed))
+        .count();
+    let fees = landed_fees(results);
+    let mut latencies: Vec<u64> = results
+        .iter()
+        .map(|result| result.processing_time_ms)
+        .collect();
+    latencies.sort_unstable();
+
+    let mut metrics = String::new();
+    metrics.push_str("# HELP transfers_total Transfers attempted in the last run.\n");
+    metrics.push_str("# TYPE transfers_total counter\n");
+    metrics.push_str(&format!("transfers_total {}\n", results.len()));
+    metrics
+        .push_str("# HELP transfers_failed_total Transfers that didn't confirm in the last run.\n");
+    metrics.push_str("# TYPE transfers_failed_total counter\n");
+    metrics.push_str(&format!("transfers_failed_total {failed}\n"));
+    metrics
+        .push_str("# HELP transfer_latency_ms Time from fetching a blockhash to confirmation.\n");
+    metrics.push_str("# TYPE transfer_latency_ms summary\n");
+    if !latencies.is_empty() {
+        for quantile in [0.5, 0.9, 0.99] {
+            let rank = ((latencies.len() as f64 * quantile).ceil() as usize).max(1);
+            metrics.push_str(&format!(
+                "transfer_latency_ms{{quantile=\"{quantile}\"}} {}\n",
+                latencies[rank - 1]
+            ));
+        }
+    }
+    metrics.push_str(&format!(
+        "transfer_latency_ms_sum {}\n",
+        latencies.iter().sum::<u64>()
+    ));
+    metrics.push_str(&format!("transfer_latency_ms_count {}\n", latencies.len()));
+    metrics.push_str(
+        "# HELP transfer_fees_lamports_total Estimated fees paid by transfers that landed.\n",
+    );
+    metrics.push_str("# TYPE transfer_fees_lamports_total counter\n");
+    metrics.push_str(&format!("transfer_fees_lamports_total {fees}\n"));
+
+    let tmp_path = format!("{path}.tmp");
+    fs::write(&tmp_path, metrics)?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+// Writes the transfers that certainly didn't go through in the input config schema and
+// returns how many there were. Timed out and pending transfers may still land, so they
+// are left out rather than risk sending them twice.
+fn write_failures(
+    path: &str,
+    wallets_pairs: Vec<WalletsPair>,
+    results: &[TransferResult],
+) -> Result<usize> {
+    let failures: Vec<WalletsPair> = wallets_pairs
+        .into_iter()
+        .zip(results)
+        .filter(|(_, result)| {
+            matches!(
+                result.status,
+                TxStatus::Failed(_) | TxStatus::Expired | TxStatus::Error(_)
+            )
+        })
+        .map(|(wallets, _)| wallets)
+        .collect();
+    fs::write(path, serde_yaml::to_string(&failures)?)?;
+    Ok(failures.len())
+}
+
+fn write_results(path: &str, results: &[TransferResult], format: ResultsFormat) -> Result<()> {
+    let content = match format {
+        ResultsFormat::Json => serde_json::to_string_pretty(results)?,
+        ResultsFormat::Csv => results_csv(results),
+    };
+    fs::write(path, content)?;
+    Ok(())
+}
+
+// Header row of the field names, then a row per result. Memos and logs are free text,
+// so they are quoted.
+fn results_csv(results: &[TransferResult]) -> String {
+    let mut csv = String::from(
+        "signature,source,destination,code,status,processing_time_ms,blockhash_ms,send_ms,\
+         confirm_ms,fee_lamports,attempts,simulation_logs,memo,confirmation_status,\
+         confirmations\n",
+    );
+    for result in results {
+        let simulation_logs = result
+            .simulation_logs
+            .as_ref()
+            .map(|logs| logs.join(";"))
+            .unwrap_or_default();
+        let confirmation_status = result
+            .confirmation_status
+            .as_ref()
+            .map(|status| format!("{status:?}").to_lowercase())
+            .unwrap_or_default();
+        let confirmations = result
+            .confirmations
+            .map(|confirmations| confirmations.to_string())
+            .unwrap_or_default();
+        csv.push_str(&format!(
+            "{},{},{},{},{},{},{},{},{},{},{},{},{},{},{}\n",
+            result.signature,
+            result.source,
+            result.destination,
+            result.code,
+            result.status.label(),
+            result.processing_time_ms,
+            result.blockhash_ms,
+            result.send_ms,
+            result.confirm_ms,
+            result.fee_lamports,
+            result.attempts,
+            csv_quote(&simulation_logs),
+            csv_quote(result.memo.as_deref().unwrap_or_default()),
+            confirmation_status,
+            confirmations
+        ));
+    }
+    csv
+}
+
+// Quotes a CSV field, doubling the quotes inside it
+fn csv_quote(field: &str) -> String {
+    format!("\"{}\"", field.replace('"', "\"\""))
+}
+
 fn print_transfer_results(results: &[TransferResult]) {
-    println!("Transfer Results:");
-    println!("{:<64} {:<10} {:<10}", "Signature", "Status", "Time (ms)");
-    println!("{}", "-".repeat(86));
+    if !results.is_empty() {
+        println!("Transfer Results:");
+        println!(
+            "{:<64} {:<4} {:<10} {:<10} {:<8}",
+            "Signature", "Code", "Status", "Time (ms)", "Attempts"
+        );
+        println!("{}", "-".repeat(100));
+    }
+
+    for result in results {
+        println!(
+            "{:<64} {:<4} {:<10} {:<10} {:<8}",
+            result.signature,
+            result.code,
+            result.status.label(),
+            result.processing_time_ms,
+            result.attempts
+        );
+        match &result.status {
+            TxStatus::Failed(err) => {
+                println!("    error: {err}");
+                // Simulated failures come with the logs explaining them
+                for log in result.simulation_logs.iter().flatten() {
+                    println!("    log: {log}");
+                }
+            }
+            TxStatus::Error(err) => println!("    error: {err}"),
+            _ => {}
+        }
+    }
+
+    print!("{}", transfer_summary(results));
+}
+
+// Counts and averages over the results, as printed after the results table
+fn transfer_summary(results: &[TransferResult]) -> String {
+    // An empty config leaves nothing to average over
+    if results.is_empty() {
+        return "No transfers performed\n".to_string();
+    }
 
     let mut success_count = 0;
     let mut failed_count = 0;
+    let mut expired_count = 0;
     let mut total_time = 0;
+    let mut blockhash_time = 0;
+    let mut send_time = 0;
+    let mut confirm_time = 0;
+
+    for result in results {
+        match result.status {
+            TxStatus::Confirmed => success_count += 1,
+            TxStatus::Expired => {
+                failed_count += 1;
+                expired_count += 1;
+            }
+            _ => failed_count += 1,
+        }
+
+        total_time += result.processing_time_ms;
+        blockhash_time += result.blockhash_ms;
+        send_time += result.send_ms;
+        confirm_time += result.confirm_ms;
+    }
+
+    let count = results.len() as u64;
+    let mut summary = format!("\nStatus codes: {}\n", TxStatus::LEGEND);
+    summary.push_str("\nSummary:\n");
+    summary.push_str(&format!("Total transfers: {}\n", results.len()));
+    summary.push_str(&format!("Successful: {}\n", success_count));
+    summary.push_str(&format!("Failed: {}\n", failed_count));
+    summary.push_str(&format!("Expired (retryable): {}\n", expired_count));
+    summary.push_str(&format!(
+        "Average processing time: {} ms\n",
+        total_time / count
+    ));
+    summary.push_str(&format!("Total processing time: {} ms\n", total_time));
+    summary.push_str(&format!(
+        "Total fees: {} SOL\n",
+        format_sol(landed_fees(results))
+    ));
+    let memos = results
+        .iter()
+        .filter(|result| result.memo.is_some())
+        .count();
+    if memos > 0 {
+        // The signature fee stays the same, only the priority fee grows with the compute units
+        summary.push_str(&format!(
+            "Transfers with a memo: {memos}, each memo instruction raises the default compute \
+             unit limit by {DEFAULT_INSTRUCTION_COMPUTE_UNIT_LIMIT}, which adds to the priority fee\n"
+        ));
+    }
+    summary.push_str(&format!(
+        "Average breakdown: blockhash {} ms, send {} ms, confirm {} ms\n",
+        blockhash_time / count,
+        send_time / count,
+        confirm_time / count
+    ));
+    summary
+}
+
+fn print_confirmation_summary(results: &[TransferResult]) {
+    let mut processed = 0;
+    let mut confirmed = 0;
+    let mut finalized = 0;
+    let mut depths = Vec::new();
 
     for result in results {
+        match result.confirmation_status {
+            Some(TransactionConfirmationStatus::Processed) => processed += 1,
+            Some(TransactionConfirmationStatus::Confirmed) => confirmed += 1,
+            Some(TransactionConfirmationStatus::Finalized) => finalized += 1,
+            None => {}
+        }
+        // Finalized transactions no longer report a confirmation count
+        depths.extend(result.confirmations);
+    }
+
+    println!("\nConfirmation depth:");
+    println!("Processed: {}", processed);
+    println!("Confirmed: {}", confirmed);
+    println!("Finalized: {}", finalized);
+    if let (Some(min), Some(max)) = (depths.iter().min(), depths.iter().max()) {
         println!(
-            "{:<64} {:<10} {:<10}",
-            result.signature, result.status, result.processing_time_ms
+            "Confirmations (non-finalized): min {min}, max {max}, average {:.1}",
+            depths.iter().sum::<usize>() as f64 / depths.len() as f64
         );
+    }
+}
 
-        if result.status == "success" {
-            success_count += 1;
-        } else {
-            failed_count += 1;
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        total_time += result.processing_time_ms;
+    #[test]
+    fn results_csv_escapes_free_text() {
+        let result = TransferResult {
+            signature: "sig".to_string(),
+            source: "from".to_string(),
+            destination: "to".to_string(),
+            code: 0,
+            status: TxStatus::Confirmed,
+            processing_time_ms: 3,
+            blockhash_ms: 1,
+            send_ms: 1,
+            confirm_ms: 1,
+            fee_lamports: 5000,
+            attempts: 1,
+            simulation_logs: Some(vec!["log 1".to_string(), "log 2".to_string()]),
+            memo: Some("pay \"rent\", now".to_string()),
+            confirmation_status: None,
+            confirmations: None,
+        };
+
+        let csv = results_csv(&[result]);
+        let mut lines = csv.lines();
+        assert_eq!(lines.next().unwrap().split(',').count(), 15);
+        assert_eq!(
+            lines.next().unwrap(),
+            "sig,from,to,0,confirmed,3,1,1,1,5000,1,\"log 1;log 2\",\"pay \"\"rent\"\", now\",,"
+        );
     }
 
-    println!("\nSummary:");
-    println!("Total transfers: {}", results.len());
-    println!("Successful: {}", success_count);
-    println!("Failed: {}", failed_count);
-    println!(
-        "Average processing time: {} ms",
-        total_time / results.len() as u64
-    );
-    println!("Total processing time: {} ms", total_time);
+    #[test]
+    fn transfer_summary_of_no_results() {
+        assert_eq!(transfer_summary(&[]), "No transfers performed\n");
+    }
 }
@@ -0,0 +1,161 @@
+use std::{fmt, path::Path};
+
+use anyhow::{Result, anyhow, bail};
+use futures::{StreamExt, stream::FuturesUnordered};
+use solana_sdk::{bs58, native_token::LAMPORTS_PER_SOL, signature::Keypair, signer::EncodableKey};
+
+// Renders lamports as SOL with up to 9 decimals, dropping trailing zeros
+pub fn format_sol(lamports: u64) -> String {
+    let whole = lamports / LAMPORTS_PER_SOL;
+    let fraction = lamports % LAMPORTS_PER_SOL;
+    if fraction == 0 {
+        return whole.to_string();
+    }
+    let fraction = format!("{fraction:09}");
+    format!("{whole}.{}", fraction.trim_end_matches('0'))
+}
+
+// Parses a SOL amount into lamports, rounding to the nearest lamport so amounts like
+// 0.1 SOL don't lose one to float truncation
+pub fn sol_to_lamports(sol: f64) -> Result<u64> {
+    if !sol.is_finite() || sol < 0.0 {
+        bail!("invalid SOL amount {sol}");
+    }
+    let lamports = (sol * LAMPORTS_PER_SOL as f64).round();
+    if lamports >= u64::MAX as f64 {
+        bail!("{sol} SOL doesn't fit into lamports");
+    }
+    Ok(lamports as u64)
+}
+
+// RPC endpoint from `--rpc-url`, falling back to the RPC_URL environment variable
+pub fn resolve_rpc_url(rpc_url: Option<&str>) -> Result<String> {
+    match rpc_url {
+        Some(rpc_url) => Ok(rpc_url.to_string()),
+        None => dotenv::var("RPC_URL")
+            .map_err(|_| anyhow!("missing solana rpc url, pass --rpc-url or set RPC_URL")),
+    }
+}
+
+// A batch whose tolerated failures still pushed it below the required success rate
+#[derive(Debug)]
+pub struct PartialFailure {
+    pub succeeded: usize,
+    pub total: usize,
+    pub min_success_rate: f64,
+}
+
+impl PartialFailure {
+    // Distinguishes a partially failed batch from a command that errored out
+    pub const EXIT_CODE: i32 = 2;
+}
+
+impl fmt::Display for PartialFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "only {} of {} operations succeeded, below the required {}%",
+            self.succeeded, self.total, self.min_success_rate
+        )
+    }
+}
+
+impl std::error::Error for PartialFailure {}
+
+// Fails with `PartialFailure` when the success rate is below `min_success_rate` percent
+pub fn check_success_rate(
+    succeeded: usize,
+    total: usize,
+    min_success_rate: Option<f64>,
+) -> Result<()> {
+    let Some(min_success_rate) = min_success_rate else {
+        return Ok(());
+    };
+    // An empty batch has nothing that could have failed
+    let success_rate = if total == 0 {
+        100.0
+    } else {
+        succeeded as f64 * 100.0 / total as f64
+    };
+    if success_rate < min_success_rate {
+        return Err(PartialFailure {
+            succeeded,
+            total,
+            min_success_rate,
+        }
+        .into());
+    }
+    Ok(())
+}
+
+// Loads a keypair from a keypair file, a JSON byte array as found in such files, or a
+// base58-encoded secret, depending on what `source` looks like. Errors never echo the
+// source back, since it may be a secret.
+pub fn load_keypair(source: &str) -> Result<Keypair> {
+    let source = source.trim();
+    let bytes = if source.starts_with('[') {
+        serde_json::from_str::<Vec<u8>>(source)
+            .map_err(|e| anyhow!("invalid keypair byte array: {e}"))?
+    } else if Path::new(source).exists() {
+        return Keypair::read_from_file(source)
+            .map_err(|e| anyhow!("failed to read keypair file {source}: {e:?}"));
+    } else {
+        bs58::decode(source)
+            .into_vec()
+            .map_err(|_| anyhow!("keypair is neither an existing file, a byte array nor base58"))?
+    };
+    Keypair::try_from(bytes.as_slice()).map_err(|e| anyhow!("invalid keypair: {e}"))
+}
+
+// Maps `items` through `f` with at most `limit` futures in flight, returning the
+// outputs in completion order once every future has finished.
+pub async fn bounded_map<I, F, Fut, T>(items: I, limit: usize, mut f: F) -> Result<Vec<T>>
+where
+    I: IntoIterator,
+    F: FnMut(I::Item) -> Fut,
+    Fut: Future<Output = T>,
+{
+    if limit == 0 {
+        bail!("concurrency limit must be at least 1");
+    }
+
+    let mut items = items.into_iter();
+    let mut handlers = FuturesUnordered::new();
+    for item in items.by_ref().take(limit) {
+        handlers.push(f(item));
+    }
+
+    let mut output = Vec::new();
+    while let Some(result) = handlers.next().await {
+        output.push(result);
+        if let Some(item) = items.next() {
+            handlers.push(f(item));
+        }
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sol_to_lamports_rounds_and_rejects_invalid_amounts() {
+        assert_eq!(sol_to_lamports(0.1).unwrap(), 100_000_000);
+        assert_eq!(sol_to_lamports(0.000000001).unwrap(), 1);
+        assert_eq!(sol_to_lamports(1.23).unwrap(), 1_230_000_000);
+        assert!(sol_to_lamports(-1.0).is_err());
+        assert!(sol_to_lamports(f64::NAN).is_err());
+        assert!(sol_to_lamports(f64::INFINITY).is_err());
+        assert!(sol_to_lamports(1e20).is_err());
+    }
+
+    #[tokio::test]
+    async fn bounded_map_returns_the_in_flight_tail() {
+        let mut output = bounded_map([1, 2, 3], 2, |item| async move { item * 10 })
+            .await
+            .unwrap();
+        output.sort();
+        assert_eq!(output, vec![10, 20, 30]);
+    }
+}
@@ -1,28 +1,22 @@
-use solana_program::{account_info::AccountInfo, entrypoint::ProgramResult, msg, pubkey::Pubkey};
+use solana_program::{account_info::AccountInfo, entrypoint::ProgramResult, pubkey::Pubkey};
 
-use crate::{instruction::DepositInstruction, processor::Processor};
+use crate::processor::Processor;
 
+#[cfg(not(feature = "merkle"))]
 solana_program::entrypoint!(process_instruction);
 
+// Built with the `merkle` feature the program serves merkle trees instead. It is deployed
+// under its own id, so tree accounts never share an owner with deposit accounts.
+#[cfg(feature = "merkle")]
+use crate::mtree::process_instruction as process_merkle_instruction;
+#[cfg(feature = "merkle")]
+solana_program::entrypoint!(process_merkle_instruction);
+
+#[cfg_attr(feature = "merkle", allow(dead_code))]
 pub fn process_instruction(
     program_id: &Pubkey,
     accounts: &[AccountInfo],
     instruction_data: &[u8],
 ) -> ProgramResult {
-    let instruction = DepositInstruction::unpack(instruction_data)?;
-
-    match instruction {
-        DepositInstruction::Initialize => {
-            msg!("Instruction: Initialize");
-            Processor::initialize_account(program_id, accounts)
-        }
-        DepositInstruction::Deposit => {
-            msg!("Instruction: Deposit");
-            Processor::deposit(program_id, accounts)
-        }
-        DepositInstruction::Withdraw { amount } => {
-            msg!("Instruction: Withdraw");
-            Processor::withdraw(program_id, accounts, amount)
-        }
-    }
+    Processor::process_instruction(program_id, accounts, instruction_data)
 }
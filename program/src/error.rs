@@ -1,4 +1,5 @@
 use num_derive::FromPrimitive;
+#[allow(deprecated)]
 use solana_program::{
     decode_error::DecodeError,
     msg,
@@ -10,6 +11,12 @@ use thiserror::Error;
 pub enum MTreeError {
     #[error("unimplemented")]
     Test,
+    #[error("leaf already exists in the tree")]
+    DuplicateLeaf,
+    #[error("leaf range is empty or starts past the last leaf")]
+    InvalidLeafRange,
+    #[error("proof doesn't lead to the tree root")]
+    InvalidProof,
 }
 
 impl From<MTreeError> for ProgramError {
@@ -18,12 +25,14 @@ impl From<MTreeError> for ProgramError {
     }
 }
 
+#[allow(deprecated)]
 impl<T> DecodeError<T> for MTreeError {
     fn type_of() -> &'static str {
         "MTreeError"
     }
 }
 
+#[allow(deprecated)]
 impl PrintProgramError for MTreeError {
     fn print<E>(&self)
     where
@@ -35,6 +44,31 @@ impl PrintProgramError for MTreeError {
     {
         match self {
             MTreeError::Test => msg!("Error: Test error"),
+            MTreeError::DuplicateLeaf => msg!("Error: Leaf already exists in the tree"),
+            MTreeError::InvalidLeafRange => {
+                msg!("Error: Leaf range is empty or starts past the last leaf")
+            }
+            MTreeError::InvalidProof => msg!("Error: Proof doesn't lead to the tree root"),
         }
     }
 }
+
+#[derive(Clone, Debug, Eq, Error, PartialEq, FromPrimitive)]
+pub enum DepositError {
+    #[error("withdrawal would leave the account below rent exemption")]
+    WithdrawBelowRentExempt,
+    #[error("signer is not the owner of the deposit account")]
+    NotOwner,
+    #[error("withdrawal cooldown hasn't elapsed yet")]
+    WithdrawTooSoon,
+    #[error("deposit account is not initialized")]
+    AccountNotInitialized,
+    #[error("deposit account still holds a balance")]
+    BalanceNotZero,
+}
+
+impl From<DepositError> for ProgramError {
+    fn from(e: DepositError) -> Self {
+        ProgramError::Custom(e as u32)
+    }
+}
@@ -1,30 +1,23 @@
 use borsh::{BorshDeserialize, BorshSerialize};
-use solana_program::program_error::ProgramError;
+use solana_program::{program_error::ProgramError, pubkey::Pubkey};
 
-#[derive(Debug, BorshDeserialize, BorshSerialize)]
+#[derive(Debug, PartialEq, Eq, BorshDeserialize, BorshSerialize)]
 pub enum DepositInstruction {
+    // Creates the signer's deposit PDA if needed, funded by the signer, and records them
+    // as its owner
     Initialize,
-    Deposit,
+    Deposit { amount: u64 },
     Withdraw { amount: u64 },
+    ChangeOwner { new_owner: Pubkey },
+    // Moves `amount` from the signer's deposit account into another deposit account
+    Transfer { amount: u64 },
+    // Returns the rent of an emptied deposit account to its owner
+    CloseAccount,
 }
 
 impl DepositInstruction {
+    // Same encoding as `Instruction::new_with_borsh` on the client side
     pub fn unpack(input: &[u8]) -> Result<Self, ProgramError> {
-        let (&tag, rest) = input
-            .split_first()
-            .ok_or(ProgramError::InvalidInstructionData)?;
-
-        Ok(match tag {
-            0 => Self::Initialize,
-            1 => Self::Deposit,
-            2 => {
-                if rest.len() < 8 {
-                    return Err(ProgramError::InvalidInstructionData);
-                }
-                let amount = u64::from_le_bytes(rest[..8].try_into().unwrap());
-                Self::Withdraw { amount }
-            }
-            _ => return Err(ProgramError::InvalidInstructionData),
-        })
+        Self::try_from_slice(input).map_err(|_| ProgramError::InvalidInstructionData)
     }
 }
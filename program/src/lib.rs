@@ -1,7 +1,12 @@
 #![allow(unexpected_cfgs)]
 
+#[cfg(not(feature = "no-entrypoint"))]
 mod entrypoint;
-mod error;
-mod instruction;
-mod processor;
-mod state;
+pub mod error;
+pub mod instruction;
+pub mod mtree;
+pub mod processor;
+pub mod state;
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,317 @@
+use std::collections::HashSet;
+
+use borsh::{BorshDeserialize, BorshSerialize};
+use solana_program::{
+    account_info::{AccountInfo, next_account_info},
+    entrypoint::ProgramResult,
+    keccak::{hash, hashv},
+    msg,
+    program::set_return_data,
+    program_error::ProgramError,
+    pubkey::Pubkey,
+};
+
+use crate::error::MTreeError;
+
+#[derive(Debug, BorshDeserialize, BorshSerialize)]
+pub enum MerkleInstruction {
+    // With `reject_duplicates` set, fails with `DuplicateLeaf` instead of inserting a leaf
+    // already in the tree
+    InsertLeaf {
+        data: Vec<u8>,
+        reject_duplicates: bool,
+    },
+    // Leaves are checked against the tree and the earlier leaves of the batch
+    InsertLeaves {
+        leaves: Vec<Vec<u8>>,
+        reject_duplicates: bool,
+    },
+    // Returns up to `MAX_LEAF_RANGE` leaf hashes starting at `start` as return data
+    GetLeafRange {
+        start: u32,
+        count: u32,
+    },
+    // Replaces the leaf at `index` with the hash of `data`
+    UpdateLeaf {
+        index: u32,
+        data: Vec<u8>,
+    },
+    // Fails unless `proof` shows that `data` is the leaf at `index` of the stored tree
+    VerifyLeaf {
+        data: Vec<u8>,
+        index: u32,
+        proof: Vec<[u8; 32]>,
+    },
+}
+
+// Leaf hashes that fit into the 1024 bytes of return data
+pub const MAX_LEAF_RANGE: u32 = 32;
+
+// Largest account the runtime allows, 10 MiB
+pub const MAX_ACCOUNT_SIZE: usize = 10 * 1024 * 1024;
+
+// Leaves that fit into an account of `MAX_ACCOUNT_SIZE`
+pub const MAX_LEAVES: usize = (MAX_ACCOUNT_SIZE - MerkleTree::space(0)) / 32;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, BorshSerialize)]
+pub struct MerkleTree {
+    pub root: [u8; 32],
+    pub leaves: Vec<[u8; 32]>,
+    // Incremented on every insert or update so consumers can order changes within a slot
+    pub sequence_number: u64,
+}
+
+// The tree is stored at the start of its account and the bytes after it are room for
+// more leaves, so reading a tree consumes the rest of the buffer. A zeroed account reads
+// as an empty tree.
+impl BorshDeserialize for MerkleTree {
+    fn deserialize_reader<R: std::io::Read>(reader: &mut R) -> std::io::Result<Self> {
+        let tree = Self {
+            root: BorshDeserialize::deserialize_reader(reader)?,
+            leaves: BorshDeserialize::deserialize_reader(reader)?,
+            sequence_number: BorshDeserialize::deserialize_reader(reader)?,
+        };
+        std::io::copy(reader, &mut std::io::sink())?;
+        Ok(tree)
+    }
+}
+
+impl MerkleTree {
+    // Serialized size of a tree holding `leaf_count` leaves
+    pub const fn space(leaf_count: usize) -> usize {
+        32 + 4 + 32 * leaf_count + 8
+    }
+
+    // Number of hashing levels between the leaves and the root
+    pub fn depth(&self) -> usize {
+        self.leaves.len().next_power_of_two().trailing_zeros() as usize
+    }
+
+    pub fn insert_leaf(&mut self, data: &[u8]) {
+        self.leaves.push(hash(data).to_bytes());
+        self.root = compute_root(&self.leaves);
+        self.sequence_number += 1;
+    }
+
+    // Same as `insert_leaf`, but refuses leaves whose hash is in `known`, the set from
+    // `leaf_set`. The inserted leaf is added to it, so a batch only builds the set once.
+    // Without a set duplicates are allowed.
+    pub fn try_insert_leaf(
+        &mut self,
+        data: &[u8],
+        known: Option<&mut HashSet<[u8; 32]>>,
+    ) -> Result<(), MTreeError> {
+        if known.is_some_and(|known| !known.insert(hash(data).to_bytes())) {
+            return Err(MTreeError::DuplicateLeaf);
+        }
+        self.insert_leaf(data);
+        Ok(())
+    }
+
+    // Hashes of the stored leaves, for duplicate checks in `try_insert_leaf`
+    pub fn leaf_set(&self) -> HashSet<[u8; 32]> {
+        self.leaves.iter().copied().collect()
+    }
+
+    pub fn update_leaf(&mut self, index: u32, data: &[u8]) -> Result<(), ProgramError> {
+        let leaf = self
+            .leaves
+            .get_mut(index as usize)
+            .ok_or(ProgramError::InvalidArgument)?;
+        *leaf = hash(data).to_bytes();
+        self.root = self.root_after_update(index as usize);
+        self.sequence_number += 1;
+        Ok(())
+    }
+
+    // Root after the leaf at `index` changed. Only the nodes on the path from that leaf
+    // to the root are affected, but for now the whole tree is rehashed.
+    fn root_after_update(&self, _index: usize) -> [u8; 32] {
+        compute_root(&self.leaves)
+    }
+
+    pub fn contains_leaf(&self, leaf_hash: &[u8; 32]) -> bool {
+        self.leaves.contains(leaf_hash)
+    }
+
+    // Sibling hashes on the path from the leaf at `leaf_index` up to the root, lowest
+    // level first. A sibling sits on the left when the node's index at its level is odd.
+    // Levels where the node is carried up unpaired have no entry.
+    pub fn proof(&self, leaf_index: usize) -> Option<Vec<[u8; 32]>> {
+        if leaf_index >= self.leaves.len() {
+            return None;
+        }
+
+        let mut proof = Vec::new();
+        let mut index = leaf_index;
+        let mut level = self.leaves.clone();
+        while level.len() > 1 {
+            if let Some(sibling) = level.get(index ^ 1) {
+                proof.push(*sibling);
+            }
+            level = next_level(&level);
+            index /= 2;
+        }
+        Some(proof)
+    }
+
+    // Leaves from `start`, with `count` capped at `MAX_LEAF_RANGE` and at the end of the tree
+    pub fn leaf_range(&self, start: u32, count: u32) -> Result<&[[u8; 32]], MTreeError> {
+        let start = start as usize;
+        if count == 0 || start >= self.leaves.len() {
+            return Err(MTreeError::InvalidLeafRange);
+        }
+        let end = self
+            .leaves
+            .len()
+            .min(start + count.min(MAX_LEAF_RANGE) as usize);
+        Ok(&self.leaves[start..end])
+    }
+}
+
+pub fn process_instruction(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    instruction_data: &[u8],
+) -> ProgramResult {
+    let instruction = MerkleInstruction::try_from_slice(instruction_data)
+        .map_err(|_| ProgramError::InvalidInstructionData)?;
+
+    let account_info_iter = &mut accounts.iter();
+    let tree_account = next_account_info(account_info_iter)?;
+
+    // Verify account ownership
+    if tree_account.owner != program_id {
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    // The account is larger than the tree, so trailing bytes are ignored.
+    // A freshly zeroed account reads as an empty tree.
+    let mut tree = MerkleTree::deserialize(&mut &tree_account.try_borrow_data()?[..])
+        .map_err(|_| ProgramError::InvalidAccountData)?;
+
+    let (leaves, reject_duplicates) = match instruction {
+        MerkleInstruction::InsertLeaf {
+            data,
+            reject_duplicates,
+        } => (vec![data], reject_duplicates),
+        MerkleInstruction::InsertLeaves {
+            leaves,
+            reject_duplicates,
+        } => (leaves, reject_duplicates),
+        // Read-only, the tree is left as is
+        MerkleInstruction::GetLeafRange { start, count } => {
+            let range = tree.leaf_range(start, count)?;
+            msg!(
+                "Leaves {}..{} of {}",
+                start,
+                start as usize + range.len(),
+                tree.leaves.len()
+            );
+            set_return_data(range.as_flattened());
+            return Ok(());
+        }
+        // Read-only as well
+        MerkleInstruction::VerifyLeaf { data, index, proof } => {
+            let leaf = tree
+                .leaves
+                .get(index as usize)
+                .ok_or(ProgramError::InvalidArgument)?;
+            // Matching the stored leaf first keeps the hash of two inner nodes from
+            // passing as a leaf with a proof that starts further up the tree
+            if hash(&data).to_bytes() != *leaf
+                || !verify_proof(tree.root, &data, index as usize, &proof)
+            {
+                return Err(MTreeError::InvalidProof.into());
+            }
+            msg!("Leaf {} verified", index);
+            return Ok(());
+        }
+        MerkleInstruction::UpdateLeaf { index, data } => {
+            tree.update_leaf(index, &data)?;
+            msg!(
+                "Leaf {} updated, sequence number {}",
+                index,
+                tree.sequence_number
+            );
+            (Vec::new(), false)
+        }
+    };
+
+    // Refuse up front rather than failing on the write after hashing everything
+    let required = MerkleTree::space(tree.leaves.len() + leaves.len());
+    if required > tree_account.data_len() {
+        msg!(
+            "Tree needs {} bytes, account holds {}",
+            required,
+            tree_account.data_len()
+        );
+        return Err(ProgramError::AccountDataTooSmall);
+    }
+    let mut known = reject_duplicates.then(|| tree.leaf_set());
+    for data in &leaves {
+        tree.try_insert_leaf(data, known.as_mut())?;
+        msg!(
+            "Leaf {} inserted, sequence number {}",
+            tree.leaves.len() - 1,
+            tree.sequence_number
+        );
+    }
+
+    tree.serialize(&mut &mut tree_account.try_borrow_mut_data()?[..])
+        .map_err(|_| ProgramError::AccountDataTooSmall)?;
+    Ok(())
+}
+
+pub fn hash_pair(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    hashv(&[left, right]).to_bytes()
+}
+
+// Recomputes the root from `leaf` and a proof produced by `MerkleTree::proof`. The proof
+// doesn't record which levels carried the node up, but such a node is the last at its level,
+// so from there on only its left siblings remain. That's the case exactly when the siblings
+// left match the set bits of the node's index.
+pub fn verify_proof(root: [u8; 32], leaf: &[u8], index: usize, proof: &[[u8; 32]]) -> bool {
+    let mut node = hash(leaf).to_bytes();
+    let mut index = index;
+    let mut siblings = proof.iter();
+    while index > 0 || siblings.len() > 0 {
+        if index % 2 == 1 {
+            match siblings.next() {
+                Some(sibling) => node = hash_pair(sibling, &node),
+                None => return false,
+            }
+        } else if siblings.len() != index.count_ones() as usize {
+            match siblings.next() {
+                Some(sibling) => node = hash_pair(&node, sibling),
+                None => return false,
+            }
+        }
+        index /= 2;
+    }
+    node == root
+}
+
+// Folds leaves pairwise left-to-right; an odd trailing node is carried up unchanged.
+pub fn compute_root(leaves: &[[u8; 32]]) -> [u8; 32] {
+    if leaves.is_empty() {
+        return [0; 32];
+    }
+
+    let mut level = leaves.to_vec();
+    while level.len() > 1 {
+        level = next_level(&level);
+    }
+    level[0]
+}
+
+fn next_level(level: &[[u8; 32]]) -> Vec<[u8; 32]> {
+    level
+        .chunks(2)
+        .map(|pair| match pair {
+            [left, right] => hash_pair(left, right),
+            [single] => *single,
+            _ => unreachable!(),
+        })
+        .collect()
+}
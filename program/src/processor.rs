@@ -1,25 +1,77 @@
 use solana_program::{
     account_info::{AccountInfo, next_account_info},
+    clock::Clock,
     entrypoint::ProgramResult,
     msg,
-    program::invoke,
+    program::{invoke, invoke_signed},
     program_error::ProgramError,
     pubkey::Pubkey,
-    system_instruction,
+    rent::Rent,
+    sysvar::Sysvar,
 };
 
+#[allow(deprecated)]
+use solana_program::system_instruction;
+
+use borsh::{BorshDeserialize, BorshSerialize};
+
+use crate::{error::DepositError, instruction::DepositInstruction, state::DepositAccount};
+
+// Minimum number of slots between two withdrawals from the same account
+pub const WITHDRAW_COOLDOWN_SLOTS: u64 = 10;
+
 pub struct Processor;
 
 impl Processor {
+    pub fn process_instruction(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        instruction_data: &[u8],
+    ) -> ProgramResult {
+        let instruction = DepositInstruction::unpack(instruction_data)?;
+
+        match instruction {
+            DepositInstruction::Initialize => {
+                msg!("Instruction: Initialize");
+                Self::initialize_account(program_id, accounts)
+            }
+            DepositInstruction::Deposit { amount } => {
+                msg!("Instruction: Deposit");
+                Self::deposit(program_id, accounts, amount)
+            }
+            DepositInstruction::Withdraw { amount } => {
+                msg!("Instruction: Withdraw");
+                Self::withdraw(program_id, accounts, amount)
+            }
+            DepositInstruction::ChangeOwner { new_owner } => {
+                msg!("Instruction: ChangeOwner");
+                Self::change_owner(program_id, accounts, new_owner)
+            }
+            DepositInstruction::Transfer { amount } => {
+                msg!("Instruction: Transfer");
+                Self::transfer(program_id, accounts, amount)
+            }
+            DepositInstruction::CloseAccount => {
+                msg!("Instruction: CloseAccount");
+                Self::close_account(program_id, accounts)
+            }
+        }
+    }
+
     pub fn initialize_account(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
         let account_info_iter = &mut accounts.iter();
         let user = next_account_info(account_info_iter)?;
         let user_deposit_account = next_account_info(account_info_iter)?;
         let system_program = next_account_info(account_info_iter)?;
 
-        // Check if the account is already initialized
-        if user_deposit_account.data_len() > 0 {
-            return Err(ProgramError::AccountAlreadyInitialized);
+        let bump = check_pda(program_id, user.key, user_deposit_account)?;
+
+        // Only the program can sign for the PDA, so it creates the account itself
+        if solana_program::system_program::check_id(user_deposit_account.owner) {
+            if !user.is_signer {
+                return Err(ProgramError::MissingRequiredSignature);
+            }
+            create_deposit_account(program_id, user, user_deposit_account, system_program, bump)?;
         }
 
         // Verify account ownership
@@ -27,15 +79,41 @@ impl Processor {
             return Err(ProgramError::IncorrectProgramId);
         }
 
-        // Initialize the account with zero balance
-        let mut data = user_deposit_account.try_borrow_mut_data()?;
-        data[0..8].copy_from_slice(&0u64.to_le_bytes());
+        // Check if the account is already initialized
+        let current =
+            DepositAccount::deserialize(&mut &user_deposit_account.try_borrow_data()?[..])
+                .map_err(|_| ProgramError::InvalidAccountData)?;
+        if current.is_initialized() {
+            return Err(ProgramError::AccountAlreadyInitialized);
+        }
+
+        // An account below the rent-exempt minimum could be closed with the deposits in it
+        let rent_exempt_minimum = Rent::get()?.minimum_balance(user_deposit_account.data_len());
+        if user_deposit_account.lamports() < rent_exempt_minimum {
+            msg!(
+                "Account is {} lamports short of the rent-exempt minimum of {}",
+                rent_exempt_minimum - user_deposit_account.lamports(),
+                rent_exempt_minimum
+            );
+            return Err(ProgramError::AccountNotRentExempt);
+        }
+
+        // Initialize the account with zero balance, owned by the initializing user
+        let deposit = DepositAccount {
+            balance: 0,
+            owner: *user.key,
+            last_withdraw_slot: 0,
+            seed: *user.key,
+        };
+        deposit
+            .serialize(&mut &mut user_deposit_account.try_borrow_mut_data()?[..])
+            .map_err(|_| ProgramError::AccountDataTooSmall)?;
 
         msg!("Account initialized");
         Ok(())
     }
 
-    pub fn deposit(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    pub fn deposit(program_id: &Pubkey, accounts: &[AccountInfo], amount: u64) -> ProgramResult {
         let account_info_iter = &mut accounts.iter();
         let user = next_account_info(account_info_iter)?;
         let user_deposit_account = next_account_info(account_info_iter)?;
@@ -46,8 +124,14 @@ impl Processor {
             return Err(ProgramError::IncorrectProgramId);
         }
 
-        // Get the amount of lamports to deposit
-        let amount = **user.lamports.borrow();
+        // A zeroed account was never initialized, so its balance can't be trusted
+        let mut deposit =
+            DepositAccount::deserialize(&mut &user_deposit_account.try_borrow_data()?[..])
+                .map_err(|_| ProgramError::InvalidAccountData)?;
+        if !deposit.is_initialized() {
+            return Err(DepositError::AccountNotInitialized.into());
+        }
+        check_pda(program_id, &deposit.seed, user_deposit_account)?;
 
         // Transfer lamports from user to deposit account
         invoke(
@@ -60,12 +144,13 @@ impl Processor {
         )?;
 
         // Update the user's balance
-        let mut data = user_deposit_account.try_borrow_mut_data()?;
-        let current_balance = u64::from_le_bytes(data[0..8].try_into().unwrap());
-        let new_balance = current_balance
+        deposit.balance = deposit
+            .balance
             .checked_add(amount)
             .ok_or(ProgramError::ArithmeticOverflow)?;
-        data[0..8].copy_from_slice(&new_balance.to_le_bytes());
+        deposit
+            .serialize(&mut &mut user_deposit_account.try_borrow_mut_data()?[..])
+            .map_err(|_| ProgramError::AccountDataTooSmall)?;
 
         msg!("Deposit successful");
         Ok(())
@@ -86,9 +171,36 @@ impl Processor {
             return Err(ProgramError::MissingRequiredSignature);
         }
 
+        // Keep the account rent-exempt so it and its stored state aren't garbage-collected
+        let rent_exempt_minimum = Rent::get()?.minimum_balance(user_deposit_account.data_len());
+        let remaining_lamports = user_deposit_account.lamports().saturating_sub(amount);
+        if remaining_lamports < rent_exempt_minimum {
+            msg!(
+                "Withdrawal would leave {} lamports, below the rent-exempt minimum of {}",
+                remaining_lamports,
+                rent_exempt_minimum
+            );
+            return Err(DepositError::WithdrawBelowRentExempt.into());
+        }
+
         // Get the current balance
         let mut data = user_deposit_account.try_borrow_mut_data()?;
-        let current_balance = u64::from_le_bytes(data[0..8].try_into().unwrap());
+        let mut deposit = DepositAccount::deserialize(&mut &data[..])
+            .map_err(|_| ProgramError::InvalidAccountData)?;
+        if !deposit.is_initialized() {
+            return Err(DepositError::AccountNotInitialized.into());
+        }
+        // The owner may have changed since, so the address is checked against the seed
+        check_pda(program_id, &deposit.seed, user_deposit_account)?;
+        // Only the current owner can take funds out
+        if deposit.owner != *user.key {
+            return Err(ProgramError::IllegalOwner);
+        }
+        let current_balance = deposit.balance;
+
+        // Rate limit withdrawals to slow down draining the account
+        let current_slot = Clock::get()?.slot;
+        check_cooldown(&deposit, current_slot)?;
 
         // Check if the user has enough balance
         if amount > current_balance {
@@ -107,12 +219,277 @@ impl Processor {
             .ok_or(ProgramError::ArithmeticOverflow)?;
 
         // Update the user's balance
-        let new_balance = current_balance
+        deposit.balance = current_balance
             .checked_sub(amount)
             .ok_or(ProgramError::ArithmeticOverflow)?;
-        data[0..8].copy_from_slice(&new_balance.to_le_bytes());
+        deposit.last_withdraw_slot = current_slot;
+        deposit
+            .serialize(&mut &mut data[..])
+            .map_err(|_| ProgramError::AccountDataTooSmall)?;
 
         msg!("Withdrawal successful");
         Ok(())
     }
+
+    pub fn change_owner(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        new_owner: Pubkey,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let owner = next_account_info(account_info_iter)?;
+        let user_deposit_account = next_account_info(account_info_iter)?;
+
+        // Verify account ownership
+        if user_deposit_account.owner != program_id {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+
+        // Only the current owner can hand the account over
+        if !owner.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        // A default owner would read as an uninitialized account and lock the funds in
+        if new_owner == Pubkey::default() {
+            return Err(ProgramError::InvalidArgument);
+        }
+
+        let mut data = user_deposit_account.try_borrow_mut_data()?;
+        let mut deposit = DepositAccount::deserialize(&mut &data[..])
+            .map_err(|_| ProgramError::InvalidAccountData)?;
+        if !deposit.is_initialized() {
+            return Err(DepositError::AccountNotInitialized.into());
+        }
+        check_pda(program_id, &deposit.seed, user_deposit_account)?;
+        if deposit.owner != *owner.key {
+            return Err(DepositError::NotOwner.into());
+        }
+
+        deposit.owner = new_owner;
+        deposit
+            .serialize(&mut &mut data[..])
+            .map_err(|_| ProgramError::AccountDataTooSmall)?;
+
+        msg!("Owner changed to {}", new_owner);
+        Ok(())
+    }
+
+    pub fn transfer(program_id: &Pubkey, accounts: &[AccountInfo], amount: u64) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let owner = next_account_info(account_info_iter)?;
+        let source_account = next_account_info(account_info_iter)?;
+        let destination_account = next_account_info(account_info_iter)?;
+
+        // Verify account ownership
+        if source_account.owner != program_id || destination_account.owner != program_id {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        if source_account.key == destination_account.key {
+            return Err(ProgramError::InvalidArgument);
+        }
+
+        // Only the owner of the source account can move funds out of it
+        if !owner.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        // Sized before the data is borrowed, which `data_len` would panic on
+        let rent_exempt_minimum = Rent::get()?.minimum_balance(source_account.data_len());
+
+        let mut source_data = source_account.try_borrow_mut_data()?;
+        let mut source = DepositAccount::deserialize(&mut &source_data[..])
+            .map_err(|_| ProgramError::InvalidAccountData)?;
+        let mut destination_data = destination_account.try_borrow_mut_data()?;
+        let mut destination = DepositAccount::deserialize(&mut &destination_data[..])
+            .map_err(|_| ProgramError::InvalidAccountData)?;
+        if !source.is_initialized() || !destination.is_initialized() {
+            return Err(DepositError::AccountNotInitialized.into());
+        }
+        check_pda(program_id, &source.seed, source_account)?;
+        check_pda(program_id, &destination.seed, destination_account)?;
+        if source.owner != *owner.key {
+            return Err(DepositError::NotOwner.into());
+        }
+
+        // Moving funds out counts as a withdrawal, otherwise the cooldown could be dodged
+        // by transferring to another account first
+        let current_slot = Clock::get()?.slot;
+        check_cooldown(&source, current_slot)?;
+
+        // Check if the owner has enough balance
+        if amount > source.balance {
+            return Err(ProgramError::InsufficientFunds);
+        }
+
+        // Keep the source account rent-exempt, as withdrawals do
+        let remaining_lamports = source_account.lamports().saturating_sub(amount);
+        if remaining_lamports < rent_exempt_minimum {
+            msg!(
+                "Transfer would leave {} lamports, below the rent-exempt minimum of {}",
+                remaining_lamports,
+                rent_exempt_minimum
+            );
+            return Err(DepositError::WithdrawBelowRentExempt.into());
+        }
+
+        // Move the lamports between the accounts
+        **source_account.lamports.borrow_mut() = source_account
+            .lamports()
+            .checked_sub(amount)
+            .ok_or(ProgramError::ArithmeticOverflow)?;
+        **destination_account.lamports.borrow_mut() = destination_account
+            .lamports()
+            .checked_add(amount)
+            .ok_or(ProgramError::ArithmeticOverflow)?;
+
+        // Update both stored balances
+        source.balance -= amount;
+        source.last_withdraw_slot = current_slot;
+        destination.balance = destination
+            .balance
+            .checked_add(amount)
+            .ok_or(ProgramError::ArithmeticOverflow)?;
+        source
+            .serialize(&mut &mut source_data[..])
+            .map_err(|_| ProgramError::AccountDataTooSmall)?;
+        destination
+            .serialize(&mut &mut destination_data[..])
+            .map_err(|_| ProgramError::AccountDataTooSmall)?;
+
+        msg!(
+            "Transferred {} lamports to {}",
+            amount,
+            destination_account.key
+        );
+        Ok(())
+    }
+
+    pub fn close_account(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let owner = next_account_info(account_info_iter)?;
+        let user_deposit_account = next_account_info(account_info_iter)?;
+
+        // Verify account ownership
+        if user_deposit_account.owner != program_id {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+
+        // Only the owner gets the rent back
+        if !owner.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        let mut data = user_deposit_account.try_borrow_mut_data()?;
+        let deposit = DepositAccount::deserialize(&mut &data[..])
+            .map_err(|_| ProgramError::InvalidAccountData)?;
+        if !deposit.is_initialized() {
+            return Err(DepositError::AccountNotInitialized.into());
+        }
+        check_pda(program_id, &deposit.seed, user_deposit_account)?;
+        if deposit.owner != *owner.key {
+            return Err(DepositError::NotOwner.into());
+        }
+
+        // Deposits have to be withdrawn first, closing only returns the rent
+        if deposit.balance != 0 {
+            msg!("Account still holds {} lamports", deposit.balance);
+            return Err(DepositError::BalanceNotZero.into());
+        }
+
+        // With no lamports and no state left the runtime removes the account
+        let lamports = user_deposit_account.lamports();
+        **owner.lamports.borrow_mut() = owner
+            .lamports()
+            .checked_add(lamports)
+            .ok_or(ProgramError::ArithmeticOverflow)?;
+        **user_deposit_account.lamports.borrow_mut() = 0;
+        data.fill(0);
+
+        msg!("Account closed, {} lamports returned", lamports);
+        Ok(())
+    }
+}
+
+// Deposit accounts are PDAs of the key that initialized them, which stays recorded as the
+// seed. Authorization goes by the recorded owner, since a handed over account doesn't
+// derive from it. Returns the bump of the address.
+fn check_pda(
+    program_id: &Pubkey,
+    seed: &Pubkey,
+    deposit_account: &AccountInfo,
+) -> Result<u8, ProgramError> {
+    let (expected, bump) = Pubkey::find_program_address(&[seed.as_ref()], program_id);
+    if *deposit_account.key != expected {
+        msg!("Deposit account of {} is {}", seed, expected);
+        return Err(ProgramError::InvalidSeeds);
+    }
+    Ok(bump)
+}
+
+// Fails while the cooldown since the last withdrawal from `deposit` hasn't elapsed
+fn check_cooldown(deposit: &DepositAccount, current_slot: u64) -> ProgramResult {
+    if deposit.last_withdraw_slot != 0 {
+        let elapsed = current_slot.saturating_sub(deposit.last_withdraw_slot);
+        if elapsed < WITHDRAW_COOLDOWN_SLOTS {
+            msg!(
+                "Next withdrawal allowed in {} slots",
+                WITHDRAW_COOLDOWN_SLOTS - elapsed
+            );
+            return Err(DepositError::WithdrawTooSoon.into());
+        }
+    }
+    Ok(())
+}
+
+// Creates the deposit PDA of `user`, who pays for its rent exemption. Lamports sent to the
+// address beforehand make `create_account` fail, so such an account is topped up,
+// allocated and assigned step by step instead.
+fn create_deposit_account<'a>(
+    program_id: &Pubkey,
+    user: &AccountInfo<'a>,
+    deposit_account: &AccountInfo<'a>,
+    system_program: &AccountInfo<'a>,
+    bump: u8,
+) -> ProgramResult {
+    let rent_exempt_minimum = Rent::get()?.minimum_balance(DepositAccount::LEN);
+    let bump = [bump];
+    let signer_seeds: &[&[u8]] = &[user.key.as_ref(), &bump];
+    let accounts = [
+        user.clone(),
+        deposit_account.clone(),
+        system_program.clone(),
+    ];
+
+    if deposit_account.lamports() == 0 {
+        return invoke_signed(
+            &system_instruction::create_account(
+                user.key,
+                deposit_account.key,
+                rent_exempt_minimum,
+                DepositAccount::LEN as u64,
+                program_id,
+            ),
+            &accounts,
+            &[signer_seeds],
+        );
+    }
+
+    let shortfall = rent_exempt_minimum.saturating_sub(deposit_account.lamports());
+    if shortfall > 0 {
+        invoke(
+            &system_instruction::transfer(user.key, deposit_account.key, shortfall),
+            &accounts,
+        )?;
+    }
+    invoke_signed(
+        &system_instruction::allocate(deposit_account.key, DepositAccount::LEN as u64),
+        &accounts,
+        &[signer_seeds],
+    )?;
+    invoke_signed(
+        &system_instruction::assign(deposit_account.key, program_id),
+        &accounts,
+        &[signer_seeds],
+    )
 }
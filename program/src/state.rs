@@ -1 +1,24 @@
+use borsh::{BorshDeserialize, BorshSerialize};
+use solana_program::pubkey::Pubkey;
 
+// Layout of a user's deposit account data
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, BorshDeserialize, BorshSerialize)]
+pub struct DepositAccount {
+    // Lamports recorded as deposited by the user
+    pub balance: u64,
+    // Key allowed to manage the account
+    pub owner: Pubkey,
+    // Slot of the most recent withdrawal, zero if there was none yet
+    pub last_withdraw_slot: u64,
+    // Key the account address derives from, the owner recorded at initialization
+    pub seed: Pubkey,
+}
+
+impl DepositAccount {
+    pub const LEN: usize = 8 + 32 + 8 + 32;
+
+    // Initialization always records an owner, so a zeroed account reads as uninitialized
+    pub fn is_initialized(&self) -> bool {
+        self.owner != Pubkey::default()
+    }
+}
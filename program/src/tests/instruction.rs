@@ -0,0 +1,39 @@
+// Tests for the Deposit Solana Program instruction encoding
+#[cfg(test)]
+mod tests {
+    use solana_program::{program_error::ProgramError, pubkey::Pubkey};
+
+    use crate::instruction::DepositInstruction;
+
+    #[test]
+    fn test_unpack_round_trip() {
+        let instructions = [
+            DepositInstruction::Initialize,
+            DepositInstruction::Deposit { amount: 1_000 },
+            DepositInstruction::Withdraw { amount: u64::MAX },
+            DepositInstruction::ChangeOwner {
+                new_owner: Pubkey::new_unique(),
+            },
+            DepositInstruction::Transfer { amount: 42 },
+            DepositInstruction::CloseAccount,
+        ];
+
+        for instruction in instructions {
+            let data = borsh::to_vec(&instruction).unwrap();
+            assert_eq!(DepositInstruction::unpack(&data).unwrap(), instruction);
+        }
+    }
+
+    #[test]
+    fn test_unpack_invalid_data() {
+        // Empty, unknown tag, truncated amount and trailing bytes
+        let inputs: [&[u8]; 4] = [&[], &[255], &[2, 1, 0, 0], &[0, 1]];
+
+        for input in inputs {
+            assert_eq!(
+                DepositInstruction::unpack(input),
+                Err(ProgramError::InvalidInstructionData)
+            );
+        }
+    }
+}
@@ -0,0 +1,3 @@
+mod instruction;
+mod mtree;
+mod processor;
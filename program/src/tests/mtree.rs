@@ -1,13 +1,20 @@
 // Tests for the Merkle Tree Solana Program
 #[cfg(test)]
 mod tests {
-    use solana_program::{account_info::AccountInfo, clock::Epoch, keccak::hash, pubkey::Pubkey};
-    use solana_program_test::*;
-    use solana_sdk::{
-        account::Account,
-        signature::{Keypair, Signer},
+    use borsh::BorshDeserialize;
+    use solana_program::{
+        account_info::AccountInfo, clock::Epoch, keccak::hash, program_error::ProgramError,
+        pubkey::Pubkey,
     };
-    use std::mem;
+
+    use crate::{
+        error::MTreeError,
+        mtree::{
+            MAX_ACCOUNT_SIZE, MAX_LEAF_RANGE, MAX_LEAVES, MerkleInstruction, MerkleTree, hash_pair,
+            process_instruction, verify_proof,
+        },
+    };
+    use solana_sdk::signature::{Keypair, Signer};
 
     // Helper function to create an AccountInfo for testing
     fn create_account_info<'a>(
@@ -18,16 +25,16 @@ mod tests {
         data: &'a mut [u8],
         owner: &'a Pubkey,
     ) -> AccountInfo<'a> {
-        AccountInfo {
+        AccountInfo::new(
             key,
             is_signer,
             is_writable,
-            lamports: lamports as *mut u64,
-            data: data as *mut [u8],
+            lamports,
+            data,
             owner,
-            executable: false,
-            rent_epoch: Epoch::default(),
-        }
+            false,
+            Epoch::default(),
+        )
     }
 
     #[test]
@@ -36,6 +43,7 @@ mod tests {
         let tree = MerkleTree {
             root: [0; 32],
             leaves: Vec::new(),
+            sequence_number: 0,
         };
 
         // Check that a new tree has the expected properties
@@ -44,12 +52,39 @@ mod tests {
         assert_eq!(tree.depth(), 0);
     }
 
+    #[test]
+    fn test_zeroed_account_is_empty_tree() {
+        // A freshly created account holds nothing but zeroes
+        let data = vec![0; 1000];
+        let tree = MerkleTree::deserialize(&mut &data[..]).unwrap();
+
+        assert_eq!(tree, MerkleTree::default());
+    }
+
+    #[test]
+    fn test_serialization_round_trip() {
+        let mut tree = MerkleTree::default();
+        tree.insert_leaf(b"Leaf 1");
+        tree.insert_leaf(b"Leaf 2");
+        tree.insert_leaf(b"Leaf 3");
+
+        let bytes = borsh::to_vec(&tree).unwrap();
+        assert_eq!(bytes.len(), MerkleTree::space(tree.leaves.len()));
+        assert_eq!(MerkleTree::try_from_slice(&bytes).unwrap(), tree);
+
+        // Trailing account bytes past the tree don't affect what's read back
+        let mut data = vec![0; 1000];
+        data[..bytes.len()].copy_from_slice(&bytes);
+        assert_eq!(MerkleTree::deserialize(&mut &data[..]).unwrap(), tree);
+    }
+
     #[test]
     fn test_insert_leaf() {
         // Create a new merkle tree
         let mut tree = MerkleTree {
             root: [0; 32],
             leaves: Vec::new(),
+            sequence_number: 0,
         };
 
         // Insert a leaf
@@ -70,6 +105,7 @@ mod tests {
         let mut tree = MerkleTree {
             root: [0; 32],
             leaves: Vec::new(),
+            sequence_number: 0,
         };
 
         // Insert multiple leaves
@@ -128,8 +164,9 @@ mod tests {
         let leaf_data = b"Test instruction";
         let instruction = MerkleInstruction::InsertLeaf {
             data: leaf_data.to_vec(),
+            reject_duplicates: false,
         };
-        let instruction_data = instruction.try_to_vec().unwrap();
+        let instruction_data = borsh::to_vec(&instruction).unwrap();
 
         // Process the instruction
         let result = process_instruction(&program_id, &accounts, &instruction_data);
@@ -148,12 +185,149 @@ mod tests {
         assert_eq!(merkle_tree.root, expected_leaf_hash);
     }
 
+    #[test]
+    fn test_process_instruction_wrong_owner() {
+        let program_id = Pubkey::new_unique();
+        let other_program_id = Pubkey::new_unique();
+        let merkle_pubkey = Pubkey::new_unique();
+
+        // The account belongs to some other program
+        let mut lamports = 100000;
+        let mut data = vec![0; 1000];
+        let merkle_account = create_account_info(
+            &merkle_pubkey,
+            false,
+            true,
+            &mut lamports,
+            &mut data,
+            &other_program_id,
+        );
+        let accounts = vec![merkle_account];
+
+        let instruction = MerkleInstruction::InsertLeaf {
+            data: b"Test instruction".to_vec(),
+            reject_duplicates: false,
+        };
+        let instruction_data = borsh::to_vec(&instruction).unwrap();
+
+        assert_eq!(
+            process_instruction(&program_id, &accounts, &instruction_data),
+            Err(ProgramError::IncorrectProgramId)
+        );
+
+        // Nothing was written to the account
+        assert!(accounts[0].data.borrow().iter().all(|&byte| byte == 0));
+    }
+
+    #[test]
+    fn test_process_instruction_account_full() {
+        let program_id = Pubkey::new_unique();
+        let merkle_pubkey = Pubkey::new_unique();
+        let mut lamports = 100000;
+        // Room for exactly two leaves
+        let mut data = vec![0; MerkleTree::space(2)];
+        let merkle_account = create_account_info(
+            &merkle_pubkey,
+            false,
+            true,
+            &mut lamports,
+            &mut data,
+            &program_id,
+        );
+        let accounts = vec![merkle_account];
+
+        let insert = |leaves: Vec<Vec<u8>>| {
+            let instruction = MerkleInstruction::InsertLeaves {
+                leaves,
+                reject_duplicates: false,
+            };
+            process_instruction(
+                &program_id,
+                &accounts,
+                &borsh::to_vec(&instruction).unwrap(),
+            )
+        };
+        assert_eq!(insert(vec![b"Leaf 1".to_vec()]), Ok(()));
+        let stored = accounts[0].data.borrow().to_vec();
+
+        // A batch that would overflow the account is refused as a whole
+        assert_eq!(
+            insert(vec![b"Leaf 2".to_vec(), b"Leaf 3".to_vec()]),
+            Err(ProgramError::AccountDataTooSmall)
+        );
+        assert_eq!(accounts[0].data.borrow().to_vec(), stored);
+
+        assert_eq!(insert(vec![b"Leaf 2".to_vec()]), Ok(()));
+        assert_eq!(
+            insert(vec![b"Leaf 3".to_vec()]),
+            Err(ProgramError::AccountDataTooSmall)
+        );
+    }
+
+    #[test]
+    fn test_process_instruction_reject_duplicates() {
+        let program_id = Pubkey::new_unique();
+        let merkle_pubkey = Pubkey::new_unique();
+        let mut lamports = 100000;
+        let mut data = vec![0; 1000];
+        let merkle_account = create_account_info(
+            &merkle_pubkey,
+            false,
+            true,
+            &mut lamports,
+            &mut data,
+            &program_id,
+        );
+        let accounts = vec![merkle_account];
+
+        let insert = |leaves: Vec<Vec<u8>>, reject_duplicates| {
+            let instruction = MerkleInstruction::InsertLeaves {
+                leaves,
+                reject_duplicates,
+            };
+            process_instruction(
+                &program_id,
+                &accounts,
+                &borsh::to_vec(&instruction).unwrap(),
+            )
+        };
+        assert_eq!(insert(vec![b"Leaf 1".to_vec()], true), Ok(()));
+        let stored = accounts[0].data.borrow().to_vec();
+
+        // Duplicates of stored leaves and within the batch are both refused
+        let duplicate = Err(ProgramError::Custom(MTreeError::DuplicateLeaf as u32));
+        assert_eq!(
+            insert(vec![b"Leaf 2".to_vec(), b"Leaf 1".to_vec()], true),
+            duplicate
+        );
+        assert_eq!(
+            insert(vec![b"Leaf 2".to_vec(), b"Leaf 2".to_vec()], true),
+            duplicate
+        );
+        assert_eq!(accounts[0].data.borrow().to_vec(), stored);
+
+        // Without the flag the same batch goes through
+        assert_eq!(
+            insert(vec![b"Leaf 2".to_vec(), b"Leaf 1".to_vec()], false),
+            Ok(())
+        );
+        let tree = MerkleTree::deserialize(&mut &accounts[0].data.borrow()[..]).unwrap();
+        assert_eq!(tree.leaves.len(), 3);
+    }
+
+    #[test]
+    fn test_max_leaves_fit_max_account() {
+        assert!(MerkleTree::space(MAX_LEAVES) <= MAX_ACCOUNT_SIZE);
+        assert!(MerkleTree::space(MAX_LEAVES + 1) > MAX_ACCOUNT_SIZE);
+    }
+
     #[test]
     fn test_tree_with_odd_number_of_leaves() {
         // Create a new merkle tree
         let mut tree = MerkleTree {
             root: [0; 32],
             leaves: Vec::new(),
+            sequence_number: 0,
         };
 
         // Insert three leaves
@@ -188,4 +362,323 @@ mod tests {
         // Verify the result
         assert_eq!(tree.root, expected_root);
     }
+
+    #[test]
+    fn test_duplicate_leaves_allowed_by_default() {
+        let mut tree = MerkleTree::default();
+
+        tree.insert_leaf(b"Leaf");
+        assert!(tree.try_insert_leaf(b"Leaf", None).is_ok());
+
+        // Both copies are stored and hashed into the root
+        assert_eq!(tree.leaves.len(), 2);
+        let leaf_hash = hash(b"Leaf").to_bytes();
+        let mut combined = Vec::with_capacity(64);
+        combined.extend_from_slice(&leaf_hash);
+        combined.extend_from_slice(&leaf_hash);
+        assert_eq!(tree.root, hash(&combined).to_bytes());
+    }
+
+    #[test]
+    fn test_reject_duplicate_leaf() {
+        let mut tree = MerkleTree::default();
+        let mut known = tree.leaf_set();
+
+        tree.try_insert_leaf(b"Leaf 1", Some(&mut known)).unwrap();
+        tree.try_insert_leaf(b"Leaf 2", Some(&mut known)).unwrap();
+        let root_before = tree.root;
+
+        // Re-inserting an existing leaf fails and leaves the tree untouched
+        assert_eq!(
+            tree.try_insert_leaf(b"Leaf 1", Some(&mut known)),
+            Err(MTreeError::DuplicateLeaf)
+        );
+        assert_eq!(tree.leaves.len(), 2);
+        assert_eq!(tree.root, root_before);
+    }
+
+    #[test]
+    fn test_sequence_number_increments() {
+        let mut tree = MerkleTree::default();
+        assert_eq!(tree.sequence_number, 0);
+
+        tree.insert_leaf(b"Leaf 1");
+        assert_eq!(tree.sequence_number, 1);
+
+        // Duplicates and batch inserts still get a sequence number of their own
+        tree.insert_leaf(b"Leaf 1");
+        let mut known = tree.leaf_set();
+        tree.try_insert_leaf(b"Leaf 2", Some(&mut known)).unwrap();
+        assert_eq!(tree.sequence_number, 3);
+
+        // A rejected insert doesn't consume a sequence number
+        assert!(tree.try_insert_leaf(b"Leaf 2", Some(&mut known)).is_err());
+        assert_eq!(tree.sequence_number, 3);
+    }
+
+    #[test]
+    fn test_leaf_range() {
+        let mut tree = MerkleTree::default();
+        for i in 0..5u8 {
+            tree.insert_leaf(&[i]);
+        }
+
+        assert_eq!(tree.leaf_range(0, 2).unwrap(), &tree.leaves[0..2]);
+        // The last leaf on its own
+        assert_eq!(tree.leaf_range(4, 1).unwrap(), &tree.leaves[4..5]);
+        // Ranges running past the end are cut at the last leaf
+        assert_eq!(tree.leaf_range(3, 10).unwrap(), &tree.leaves[3..5]);
+
+        assert_eq!(tree.leaf_range(5, 1), Err(MTreeError::InvalidLeafRange));
+        assert_eq!(
+            tree.leaf_range(u32::MAX, 1),
+            Err(MTreeError::InvalidLeafRange)
+        );
+        assert_eq!(tree.leaf_range(0, 0), Err(MTreeError::InvalidLeafRange));
+        assert_eq!(
+            MerkleTree::default().leaf_range(0, 1),
+            Err(MTreeError::InvalidLeafRange)
+        );
+    }
+
+    #[test]
+    fn test_leaf_range_is_capped() {
+        let mut tree = MerkleTree::default();
+        for i in 0..MAX_LEAF_RANGE * 2 {
+            tree.insert_leaf(&i.to_le_bytes());
+        }
+
+        let range = tree.leaf_range(1, u32::MAX).unwrap();
+        assert_eq!(range.len(), MAX_LEAF_RANGE as usize);
+        assert_eq!(range[0], tree.leaves[1]);
+    }
+
+    #[test]
+    fn test_update_leaf() {
+        let mut tree = MerkleTree::default();
+        tree.insert_leaf(b"Leaf 1");
+        tree.insert_leaf(b"Leaf 2");
+        tree.insert_leaf(b"Leaf 3");
+        let original_root = tree.root;
+
+        tree.update_leaf(1, b"Updated").unwrap();
+        assert_ne!(tree.root, original_root);
+        assert_eq!(tree.leaves[1], hash(b"Updated").to_bytes());
+        assert_eq!(tree.leaves.len(), 3);
+
+        // Restoring the original data restores the original root
+        tree.update_leaf(1, b"Leaf 2").unwrap();
+        assert_eq!(tree.root, original_root);
+    }
+
+    #[test]
+    fn test_proof_single_leaf() {
+        let mut tree = MerkleTree::default();
+        tree.insert_leaf(b"Leaf 1");
+
+        // The leaf is the root, nothing to pair with
+        assert_eq!(tree.proof(0), Some(Vec::new()));
+        assert_eq!(tree.proof(1), None);
+    }
+
+    #[test]
+    fn test_proof_two_leaves() {
+        let mut tree = MerkleTree::default();
+        tree.insert_leaf(b"Leaf 1");
+        tree.insert_leaf(b"Leaf 2");
+        let hash1 = hash(b"Leaf 1").to_bytes();
+        let hash2 = hash(b"Leaf 2").to_bytes();
+
+        assert_eq!(tree.proof(0), Some(vec![hash2]));
+        assert_eq!(tree.proof(1), Some(vec![hash1]));
+        assert_eq!(tree.proof(2), None);
+
+        // Leaf 1 has its sibling on the right, leaf 2 on the left
+        assert_eq!(hash_pair(&hash1, &tree.proof(0).unwrap()[0]), tree.root);
+        assert_eq!(hash_pair(&tree.proof(1).unwrap()[0], &hash2), tree.root);
+    }
+
+    #[test]
+    fn test_proof_three_leaves() {
+        let mut tree = MerkleTree::default();
+        tree.insert_leaf(b"Leaf 1");
+        tree.insert_leaf(b"Leaf 2");
+        tree.insert_leaf(b"Leaf 3");
+        let hash1 = hash(b"Leaf 1").to_bytes();
+        let hash2 = hash(b"Leaf 2").to_bytes();
+        let hash3 = hash(b"Leaf 3").to_bytes();
+        let parent1 = hash_pair(&hash1, &hash2);
+
+        assert_eq!(tree.proof(0), Some(vec![hash2, hash3]));
+        assert_eq!(tree.proof(1), Some(vec![hash1, hash3]));
+        // Leaf 3 is carried up the first level, so its only sibling is the left parent
+        assert_eq!(tree.proof(2), Some(vec![parent1]));
+        assert_eq!(tree.proof(3), None);
+
+        assert_eq!(hash_pair(&parent1, &hash3), tree.root);
+    }
+
+    #[test]
+    fn test_verify_proof() {
+        // Every leaf of trees with and without carried nodes
+        for size in 1..=9u8 {
+            let mut tree = MerkleTree::default();
+            for i in 0..size {
+                tree.insert_leaf(&[i]);
+            }
+
+            for i in 0..size {
+                let proof = tree.proof(i as usize).unwrap();
+                assert!(verify_proof(tree.root, &[i], i as usize, &proof));
+            }
+        }
+    }
+
+    #[test]
+    fn test_verify_proof_rejects_tampering() {
+        let mut tree = MerkleTree::default();
+        for i in 0..5u8 {
+            tree.insert_leaf(&[i]);
+        }
+        let proof = tree.proof(2).unwrap();
+        assert!(verify_proof(tree.root, &[2], 2, &proof));
+
+        // Tampered leaf
+        assert!(!verify_proof(tree.root, &[7], 2, &proof));
+        // Right leaf at the wrong position
+        assert!(!verify_proof(tree.root, &[2], 3, &proof));
+        // Tampered or truncated proof
+        let mut tampered = proof.clone();
+        tampered[0][0] ^= 1;
+        assert!(!verify_proof(tree.root, &[2], 2, &tampered));
+        assert!(!verify_proof(tree.root, &[2], 2, &proof[1..]));
+        // Proof of the carried last leaf
+        let proof = tree.proof(4).unwrap();
+        assert!(verify_proof(tree.root, &[4], 4, &proof));
+        assert!(!verify_proof(tree.root, &[4], 4, &[]));
+    }
+
+    #[test]
+    fn test_process_verify_leaf() {
+        let program_id = Pubkey::new_unique();
+        let merkle_pubkey = Pubkey::new_unique();
+        let mut lamports = 100000;
+        let mut data = vec![0; 1000];
+        let merkle_account = create_account_info(
+            &merkle_pubkey,
+            false,
+            true,
+            &mut lamports,
+            &mut data,
+            &program_id,
+        );
+        let accounts = vec![merkle_account];
+
+        // Insert the leaves on chain
+        let leaves: Vec<Vec<u8>> = (0..5u8).map(|i| vec![i]).collect();
+        let instruction = MerkleInstruction::InsertLeaves {
+            leaves: leaves.clone(),
+            reject_duplicates: false,
+        };
+        process_instruction(
+            &program_id,
+            &accounts,
+            &borsh::to_vec(&instruction).unwrap(),
+        )
+        .unwrap();
+
+        // Build the proof off chain from the same leaves
+        let mut local = MerkleTree::default();
+        for leaf in &leaves {
+            local.insert_leaf(leaf);
+        }
+        let proof = local.proof(3).unwrap();
+
+        let verify = |data: Vec<u8>, index: u32| {
+            let instruction = MerkleInstruction::VerifyLeaf {
+                data,
+                index,
+                proof: proof.clone(),
+            };
+            process_instruction(
+                &program_id,
+                &accounts,
+                &borsh::to_vec(&instruction).unwrap(),
+            )
+        };
+        assert_eq!(verify(vec![3], 3), Ok(()));
+        assert_eq!(
+            verify(vec![4], 3),
+            Err(ProgramError::Custom(MTreeError::InvalidProof as u32))
+        );
+        assert_eq!(
+            verify(vec![3], 2),
+            Err(ProgramError::Custom(MTreeError::InvalidProof as u32))
+        );
+    }
+
+    #[test]
+    fn test_process_verify_leaf_rejects_inner_node() {
+        let program_id = Pubkey::new_unique();
+        let merkle_pubkey = Pubkey::new_unique();
+        let mut lamports = 100000;
+        let mut data = vec![0; 1000];
+        let merkle_account = create_account_info(
+            &merkle_pubkey,
+            false,
+            true,
+            &mut lamports,
+            &mut data,
+            &program_id,
+        );
+        let accounts = vec![merkle_account];
+
+        let instruction = MerkleInstruction::InsertLeaves {
+            leaves: vec![b"Leaf 0".to_vec(), b"Leaf 1".to_vec()],
+            reject_duplicates: false,
+        };
+        process_instruction(
+            &program_id,
+            &accounts,
+            &borsh::to_vec(&instruction).unwrap(),
+        )
+        .unwrap();
+
+        let verify = |data: Vec<u8>, index: u32| {
+            let instruction = MerkleInstruction::VerifyLeaf {
+                data,
+                index,
+                proof: Vec::new(),
+            };
+            process_instruction(
+                &program_id,
+                &accounts,
+                &borsh::to_vec(&instruction).unwrap(),
+            )
+        };
+        // The concatenated leaf hashes hash to the root, but aren't a leaf
+        let inner_node = [hash(b"Leaf 0").to_bytes(), hash(b"Leaf 1").to_bytes()].concat();
+        assert_eq!(
+            verify(inner_node, 0),
+            Err(ProgramError::Custom(MTreeError::InvalidProof as u32))
+        );
+        assert_eq!(
+            verify(b"Leaf 0".to_vec(), 2),
+            Err(ProgramError::InvalidArgument)
+        );
+    }
+
+    #[test]
+    fn test_update_leaf_out_of_range() {
+        let mut tree = MerkleTree::default();
+        tree.insert_leaf(b"Leaf 1");
+        let root_before = tree.root;
+
+        assert_eq!(
+            tree.update_leaf(1, b"Updated"),
+            Err(ProgramError::InvalidArgument)
+        );
+        assert_eq!(tree.root, root_before);
+        assert_eq!(tree.sequence_number, 1);
+    }
 }
@@ -0,0 +1,1066 @@
+// Tests for the Deposit Solana Program
+#[cfg(test)]
+mod tests {
+    use borsh::BorshDeserialize;
+    use solana_program::{
+        instruction::{AccountMeta, Instruction, InstructionError},
+        pubkey::Pubkey,
+        rent::Rent,
+    };
+    use solana_program_test::*;
+    use solana_sdk::{
+        account::Account,
+        native_token::LAMPORTS_PER_SOL,
+        signature::{Keypair, Signer},
+        transaction::{Transaction, TransactionError},
+    };
+
+    use crate::{
+        error::DepositError,
+        instruction::DepositInstruction,
+        processor::{Processor, WITHDRAW_COOLDOWN_SLOTS},
+        state::DepositAccount,
+    };
+
+    // Starts a bank holding a deposit PDA for `user` with the given lamports and stored balance
+    async fn setup(
+        user: &Keypair,
+        lamports: u64,
+        balance: u64,
+    ) -> (ProgramTestContext, Pubkey, Pubkey) {
+        let deposit = DepositAccount {
+            balance,
+            owner: user.pubkey(),
+            last_withdraw_slot: 0,
+            seed: user.pubkey(),
+        };
+        setup_with_data(user, lamports, borsh::to_vec(&deposit).unwrap()).await
+    }
+
+    // Same as `setup`, but with arbitrary deposit account data
+    async fn setup_with_data(
+        user: &Keypair,
+        lamports: u64,
+        data: Vec<u8>,
+    ) -> (ProgramTestContext, Pubkey, Pubkey) {
+        let (context, program_id, pdas) = setup_many(&[(user, lamports, data)]).await;
+        (context, program_id, pdas[0])
+    }
+
+    // Starts a bank with a deposit PDA of the given lamports and data for every user
+    async fn setup_many(
+        users: &[(&Keypair, u64, Vec<u8>)],
+    ) -> (ProgramTestContext, Pubkey, Vec<Pubkey>) {
+        let program_id = Pubkey::new_unique();
+        let mut program_test = ProgramTest::new(
+            "program",
+            program_id,
+            processor!(Processor::process_instruction),
+        );
+
+        let mut pdas = Vec::new();
+        for (user, lamports, data) in users {
+            let (pda, _) = Pubkey::find_program_address(&[user.pubkey().as_ref()], &program_id);
+            program_test.add_account(
+                pda,
+                Account {
+                    lamports: *lamports,
+                    data: data.clone(),
+                    owner: program_id,
+                    ..Account::default()
+                },
+            );
+            program_test.add_account(
+                user.pubkey(),
+                Account {
+                    lamports: LAMPORTS_PER_SOL,
+                    ..Account::default()
+                },
+            );
+            pdas.push(pda);
+        }
+
+        (program_test.start_with_context().await, program_id, pdas)
+    }
+
+    // Starts a bank where `user` holds 1 SOL and `pda_lamports` were already sent to its
+    // deposit PDA, which isn't created yet
+    async fn setup_uncreated(
+        user: &Keypair,
+        pda_lamports: u64,
+    ) -> (ProgramTestContext, Pubkey, Pubkey) {
+        let program_id = Pubkey::new_unique();
+        let mut program_test = ProgramTest::new(
+            "program",
+            program_id,
+            processor!(Processor::process_instruction),
+        );
+
+        let (pda, _) = Pubkey::find_program_address(&[user.pubkey().as_ref()], &program_id);
+        if pda_lamports > 0 {
+            program_test.add_account(
+                pda,
+                Account {
+                    lamports: pda_lamports,
+                    ..Account::default()
+                },
+            );
+        }
+        program_test.add_account(
+            user.pubkey(),
+            Account {
+                lamports: LAMPORTS_PER_SOL,
+                ..Account::default()
+            },
+        );
+
+        (program_test.start_with_context().await, program_id, pda)
+    }
+
+    async fn initialize(
+        context: &mut ProgramTestContext,
+        program_id: Pubkey,
+        user: &Keypair,
+        pda: Pubkey,
+    ) -> Result<(), BanksClientError> {
+        let instruction = Instruction::new_with_borsh(
+            program_id,
+            &DepositInstruction::Initialize,
+            vec![
+                AccountMeta::new(user.pubkey(), true),
+                AccountMeta::new(pda, false),
+                AccountMeta::new_readonly(solana_program::system_program::id(), false),
+            ],
+        );
+        let transaction = Transaction::new_signed_with_payer(
+            &[instruction],
+            Some(&context.payer.pubkey()),
+            &[&context.payer, user],
+            context.last_blockhash,
+        );
+        context.banks_client.process_transaction(transaction).await
+    }
+
+    async fn withdraw(
+        context: &mut ProgramTestContext,
+        program_id: Pubkey,
+        user: &Keypair,
+        pda: Pubkey,
+        amount: u64,
+    ) -> Result<(), BanksClientError> {
+        let instruction = Instruction::new_with_borsh(
+            program_id,
+            &DepositInstruction::Withdraw { amount },
+            vec![
+                AccountMeta::new(user.pubkey(), true),
+                AccountMeta::new(pda, false),
+            ],
+        );
+        let transaction = Transaction::new_signed_with_payer(
+            &[instruction],
+            Some(&context.payer.pubkey()),
+            &[&context.payer, user],
+            context.last_blockhash,
+        );
+        context.banks_client.process_transaction(transaction).await
+    }
+
+    async fn deposit(
+        context: &mut ProgramTestContext,
+        program_id: Pubkey,
+        user: &Keypair,
+        pda: Pubkey,
+        amount: u64,
+    ) -> Result<(), BanksClientError> {
+        let instruction = Instruction::new_with_borsh(
+            program_id,
+            &DepositInstruction::Deposit { amount },
+            vec![
+                AccountMeta::new(user.pubkey(), true),
+                AccountMeta::new(pda, false),
+                AccountMeta::new_readonly(solana_program::system_program::id(), false),
+            ],
+        );
+        let transaction = Transaction::new_signed_with_payer(
+            &[instruction],
+            Some(&context.payer.pubkey()),
+            &[&context.payer, user],
+            context.last_blockhash,
+        );
+        context.banks_client.process_transaction(transaction).await
+    }
+
+    async fn change_owner(
+        context: &mut ProgramTestContext,
+        program_id: Pubkey,
+        signer: &Keypair,
+        pda: Pubkey,
+        new_owner: Pubkey,
+    ) -> Result<(), BanksClientError> {
+        let instruction = Instruction::new_with_borsh(
+            program_id,
+            &DepositInstruction::ChangeOwner { new_owner },
+            vec![
+                AccountMeta::new(signer.pubkey(), true),
+                AccountMeta::new(pda, false),
+            ],
+        );
+        let transaction = Transaction::new_signed_with_payer(
+            &[instruction],
+            Some(&context.payer.pubkey()),
+            &[&context.payer, signer],
+            context.last_blockhash,
+        );
+        context.banks_client.process_transaction(transaction).await
+    }
+
+    async fn transfer(
+        context: &mut ProgramTestContext,
+        program_id: Pubkey,
+        owner: &Keypair,
+        source: Pubkey,
+        destination: Pubkey,
+        amount: u64,
+    ) -> Result<(), BanksClientError> {
+        let instruction = Instruction::new_with_borsh(
+            program_id,
+            &DepositInstruction::Transfer { amount },
+            vec![
+                AccountMeta::new(owner.pubkey(), true),
+                AccountMeta::new(source, false),
+                AccountMeta::new(destination, false),
+            ],
+        );
+        let transaction = Transaction::new_signed_with_payer(
+            &[instruction],
+            Some(&context.payer.pubkey()),
+            &[&context.payer, owner],
+            context.last_blockhash,
+        );
+        context.banks_client.process_transaction(transaction).await
+    }
+
+    // Two deposit accounts holding 1 SOL each, with `balance` recorded for the first one
+    async fn setup_transfer(
+        user: &Keypair,
+        recipient: &Keypair,
+        balance: u64,
+    ) -> (ProgramTestContext, Pubkey, Pubkey, Pubkey) {
+        let deposit = |owner: &Keypair, balance| {
+            borsh::to_vec(&DepositAccount {
+                balance,
+                owner: owner.pubkey(),
+                last_withdraw_slot: 0,
+                seed: owner.pubkey(),
+            })
+            .unwrap()
+        };
+        let (context, program_id, pdas) = setup_many(&[
+            (user, LAMPORTS_PER_SOL, deposit(user, balance)),
+            (recipient, LAMPORTS_PER_SOL, deposit(recipient, 0)),
+        ])
+        .await;
+        (context, program_id, pdas[0], pdas[1])
+    }
+
+    async fn close_account(
+        context: &mut ProgramTestContext,
+        program_id: Pubkey,
+        owner: &Keypair,
+        pda: Pubkey,
+    ) -> Result<(), BanksClientError> {
+        let instruction = Instruction::new_with_borsh(
+            program_id,
+            &DepositInstruction::CloseAccount,
+            vec![
+                AccountMeta::new(owner.pubkey(), true),
+                AccountMeta::new(pda, false),
+            ],
+        );
+        let transaction = Transaction::new_signed_with_payer(
+            &[instruction],
+            Some(&context.payer.pubkey()),
+            &[&context.payer, owner],
+            context.last_blockhash,
+        );
+        context.banks_client.process_transaction(transaction).await
+    }
+
+    async fn stored_deposit(context: &mut ProgramTestContext, pda: Pubkey) -> DepositAccount {
+        let account = context
+            .banks_client
+            .get_account(pda)
+            .await
+            .unwrap()
+            .unwrap();
+        DepositAccount::try_from_slice(&account.data).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_initialize() {
+        let user = Keypair::new();
+        let rent_exempt_minimum = Rent::default().minimum_balance(DepositAccount::LEN);
+        let (mut context, program_id, pda) =
+            setup_with_data(&user, rent_exempt_minimum, vec![0; DepositAccount::LEN]).await;
+
+        initialize(&mut context, program_id, &user, pda)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            stored_deposit(&mut context, pda).await,
+            DepositAccount {
+                balance: 0,
+                owner: user.pubkey(),
+                last_withdraw_slot: 0,
+                seed: user.pubkey(),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_initialize_creates_account() {
+        let user = Keypair::new();
+        let rent_exempt_minimum = Rent::default().minimum_balance(DepositAccount::LEN);
+        let (mut context, program_id, pda) = setup_uncreated(&user, 0).await;
+
+        initialize(&mut context, program_id, &user, pda)
+            .await
+            .unwrap();
+
+        // The user pays for the rent exemption of the new account
+        let account = context
+            .banks_client
+            .get_account(pda)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(account.owner, program_id);
+        assert_eq!(account.lamports, rent_exempt_minimum);
+        let user_account = context
+            .banks_client
+            .get_account(user.pubkey())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            user_account.lamports,
+            LAMPORTS_PER_SOL - rent_exempt_minimum
+        );
+        assert_eq!(
+            stored_deposit(&mut context, pda).await,
+            DepositAccount {
+                balance: 0,
+                owner: user.pubkey(),
+                last_withdraw_slot: 0,
+                seed: user.pubkey(),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_initialize_prefunded_address() {
+        let user = Keypair::new();
+        let rent_exempt_minimum = Rent::default().minimum_balance(DepositAccount::LEN);
+        // Someone sent lamports to the address before it was created
+        let (mut context, program_id, pda) = setup_uncreated(&user, 1_000).await;
+
+        initialize(&mut context, program_id, &user, pda)
+            .await
+            .unwrap();
+
+        // Only the shortfall is taken from the user
+        let account = context
+            .banks_client
+            .get_account(pda)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(account.owner, program_id);
+        assert_eq!(account.lamports, rent_exempt_minimum);
+        let user_account = context
+            .banks_client
+            .get_account(user.pubkey())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            user_account.lamports,
+            LAMPORTS_PER_SOL - (rent_exempt_minimum - 1_000)
+        );
+        assert!(stored_deposit(&mut context, pda).await.is_initialized());
+    }
+
+    #[tokio::test]
+    async fn test_initialize_underfunded_account_fails() {
+        let user = Keypair::new();
+        let rent_exempt_minimum = Rent::default().minimum_balance(DepositAccount::LEN);
+        let (mut context, program_id, pda) =
+            setup_with_data(&user, rent_exempt_minimum - 1, vec![0; DepositAccount::LEN]).await;
+
+        let err = initialize(&mut context, program_id, &user, pda)
+            .await
+            .unwrap_err()
+            .unwrap();
+        assert_eq!(
+            err,
+            TransactionError::InstructionError(0, InstructionError::AccountNotRentExempt)
+        );
+
+        // The account stays uninitialized
+        assert!(!stored_deposit(&mut context, pda).await.is_initialized());
+    }
+
+    #[tokio::test]
+    async fn test_initialize_wrong_pda_fails() {
+        let user = Keypair::new();
+        let other = Keypair::new();
+        let rent_exempt_minimum = Rent::default().minimum_balance(DepositAccount::LEN);
+        // The account derives from another key
+        let (mut context, program_id, other_pda) =
+            setup_with_data(&other, rent_exempt_minimum, vec![0; DepositAccount::LEN]).await;
+
+        let err = initialize(&mut context, program_id, &user, other_pda)
+            .await
+            .unwrap_err()
+            .unwrap();
+        assert_eq!(
+            err,
+            TransactionError::InstructionError(0, InstructionError::InvalidSeeds)
+        );
+        assert!(
+            !stored_deposit(&mut context, other_pda)
+                .await
+                .is_initialized()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_deposit_wrong_pda_fails() {
+        let user = Keypair::new();
+        // The account lives at the user's PDA but claims to derive from another key
+        let deposit_account = DepositAccount {
+            balance: 0,
+            owner: user.pubkey(),
+            last_withdraw_slot: 0,
+            seed: Pubkey::new_unique(),
+        };
+        let (mut context, program_id, pda) = setup_with_data(
+            &user,
+            LAMPORTS_PER_SOL,
+            borsh::to_vec(&deposit_account).unwrap(),
+        )
+        .await;
+
+        let err = deposit(&mut context, program_id, &user, pda, 1_000)
+            .await
+            .unwrap_err()
+            .unwrap();
+        assert_eq!(
+            err,
+            TransactionError::InstructionError(0, InstructionError::InvalidSeeds)
+        );
+        assert_eq!(stored_deposit(&mut context, pda).await, deposit_account);
+    }
+
+    #[tokio::test]
+    async fn test_deposit_by_new_owner() {
+        let user = Keypair::new();
+        let new_owner = Keypair::new();
+        let (mut context, program_id, pda) = setup(&user, LAMPORTS_PER_SOL, 0).await;
+        context.set_account(
+            &new_owner.pubkey(),
+            &Account {
+                lamports: LAMPORTS_PER_SOL,
+                ..Account::default()
+            }
+            .into(),
+        );
+
+        change_owner(&mut context, program_id, &user, pda, new_owner.pubkey())
+            .await
+            .unwrap();
+
+        // The account still derives from the previous owner's key
+        deposit(&mut context, program_id, &new_owner, pda, 1_000)
+            .await
+            .unwrap();
+        assert_eq!(stored_deposit(&mut context, pda).await.balance, 1_000);
+    }
+
+    #[tokio::test]
+    async fn test_withdraw_by_new_owner() {
+        let user = Keypair::new();
+        let new_owner = Keypair::new();
+        let (mut context, program_id, pda) =
+            setup(&user, LAMPORTS_PER_SOL, LAMPORTS_PER_SOL / 2).await;
+
+        change_owner(&mut context, program_id, &user, pda, new_owner.pubkey())
+            .await
+            .unwrap();
+
+        // The account still derives from the previous owner's key
+        withdraw(
+            &mut context,
+            program_id,
+            &new_owner,
+            pda,
+            LAMPORTS_PER_SOL / 10,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            stored_deposit(&mut context, pda).await.balance,
+            LAMPORTS_PER_SOL / 2 - LAMPORTS_PER_SOL / 10
+        );
+    }
+
+    #[tokio::test]
+    async fn test_withdraw_wrong_pda_fails() {
+        let user = Keypair::new();
+        // The account lives at the user's PDA but claims to derive from another key
+        let deposit = DepositAccount {
+            balance: 1_000,
+            owner: user.pubkey(),
+            last_withdraw_slot: 0,
+            seed: Pubkey::new_unique(),
+        };
+        let (mut context, program_id, pda) =
+            setup_with_data(&user, LAMPORTS_PER_SOL, borsh::to_vec(&deposit).unwrap()).await;
+
+        let err = withdraw(&mut context, program_id, &user, pda, 1_000)
+            .await
+            .unwrap_err()
+            .unwrap();
+        assert_eq!(
+            err,
+            TransactionError::InstructionError(0, InstructionError::InvalidSeeds)
+        );
+        assert_eq!(stored_deposit(&mut context, pda).await, deposit);
+    }
+
+    #[tokio::test]
+    async fn test_withdraw_keeps_rent_exemption() {
+        let user = Keypair::new();
+        let rent_exempt_minimum = Rent::default().minimum_balance(DepositAccount::LEN);
+        let (mut context, program_id, pda) = setup(&user, rent_exempt_minimum + 1_000, 1_000).await;
+
+        // Withdrawing the whole stored balance leaves exactly the rent-exempt minimum
+        withdraw(&mut context, program_id, &user, pda, 1_000)
+            .await
+            .unwrap();
+
+        let account = context
+            .banks_client
+            .get_account(pda)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(account.lamports, rent_exempt_minimum);
+    }
+
+    #[tokio::test]
+    async fn test_withdraw_below_rent_exemption_fails() {
+        let user = Keypair::new();
+        let rent_exempt_minimum = Rent::default().minimum_balance(DepositAccount::LEN);
+        // The stored balance claims more than the account can spare above the rent floor
+        let (mut context, program_id, pda) =
+            setup(&user, rent_exempt_minimum, rent_exempt_minimum).await;
+
+        let err = withdraw(&mut context, program_id, &user, pda, 1)
+            .await
+            .unwrap_err()
+            .unwrap();
+        assert_eq!(
+            err,
+            TransactionError::InstructionError(
+                0,
+                InstructionError::Custom(DepositError::WithdrawBelowRentExempt as u32)
+            )
+        );
+
+        let account = context
+            .banks_client
+            .get_account(pda)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(account.lamports, rent_exempt_minimum);
+    }
+
+    #[tokio::test]
+    async fn test_change_owner() {
+        let user = Keypair::new();
+        let new_owner = Pubkey::new_unique();
+        let deposit = DepositAccount {
+            balance: 1_000,
+            owner: user.pubkey(),
+            last_withdraw_slot: 0,
+            seed: user.pubkey(),
+        };
+        let (mut context, program_id, pda) =
+            setup_with_data(&user, LAMPORTS_PER_SOL, borsh::to_vec(&deposit).unwrap()).await;
+
+        change_owner(&mut context, program_id, &user, pda, new_owner)
+            .await
+            .unwrap();
+
+        // Only the owner changes, the funds stay with the account
+        assert_eq!(
+            stored_deposit(&mut context, pda).await,
+            DepositAccount {
+                balance: 1_000,
+                owner: new_owner,
+                last_withdraw_slot: 0,
+                seed: user.pubkey(),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_change_owner_by_non_owner_fails() {
+        let user = Keypair::new();
+        let attacker = Keypair::new();
+        let deposit = DepositAccount {
+            balance: 1_000,
+            owner: user.pubkey(),
+            last_withdraw_slot: 0,
+            seed: user.pubkey(),
+        };
+        let (mut context, program_id, pda) =
+            setup_with_data(&user, LAMPORTS_PER_SOL, borsh::to_vec(&deposit).unwrap()).await;
+
+        let err = change_owner(&mut context, program_id, &attacker, pda, attacker.pubkey())
+            .await
+            .unwrap_err()
+            .unwrap();
+        assert_eq!(
+            err,
+            TransactionError::InstructionError(
+                0,
+                InstructionError::Custom(DepositError::NotOwner as u32)
+            )
+        );
+
+        assert_eq!(stored_deposit(&mut context, pda).await, deposit);
+    }
+
+    #[tokio::test]
+    async fn test_change_owner_to_default_fails() {
+        let user = Keypair::new();
+        let (mut context, program_id, pda) = setup(&user, LAMPORTS_PER_SOL, 1_000).await;
+
+        let err = change_owner(&mut context, program_id, &user, pda, Pubkey::default())
+            .await
+            .unwrap_err()
+            .unwrap();
+        assert_eq!(
+            err,
+            TransactionError::InstructionError(0, InstructionError::InvalidArgument)
+        );
+        assert_eq!(stored_deposit(&mut context, pda).await.owner, user.pubkey());
+    }
+
+    #[tokio::test]
+    async fn test_withdraw_by_non_owner_fails() {
+        let user = Keypair::new();
+        let attacker = Keypair::new();
+        let (mut context, program_id, pda) = setup(&user, LAMPORTS_PER_SOL, 1_000).await;
+
+        let err = withdraw(&mut context, program_id, &attacker, pda, 1_000)
+            .await
+            .unwrap_err()
+            .unwrap();
+        assert_eq!(
+            err,
+            TransactionError::InstructionError(0, InstructionError::IllegalOwner)
+        );
+
+        let account = context
+            .banks_client
+            .get_account(pda)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(account.lamports, LAMPORTS_PER_SOL);
+        assert_eq!(stored_deposit(&mut context, pda).await.balance, 1_000);
+    }
+
+    #[tokio::test]
+    async fn test_withdraw_within_cooldown_fails() {
+        let user = Keypair::new();
+        let (mut context, program_id, pda) = setup(&user, LAMPORTS_PER_SOL, 1_000).await;
+        context.warp_to_slot(100).unwrap();
+        context.get_new_latest_blockhash().await.unwrap();
+
+        withdraw(&mut context, program_id, &user, pda, 100)
+            .await
+            .unwrap();
+        let err = withdraw(&mut context, program_id, &user, pda, 200)
+            .await
+            .unwrap_err()
+            .unwrap();
+        assert_eq!(
+            err,
+            TransactionError::InstructionError(
+                0,
+                InstructionError::Custom(DepositError::WithdrawTooSoon as u32)
+            )
+        );
+
+        let deposit = stored_deposit(&mut context, pda).await;
+        assert_eq!(deposit.balance, 900);
+        assert!(deposit.last_withdraw_slot >= 100);
+    }
+
+    #[tokio::test]
+    async fn test_withdraw_after_cooldown() {
+        let user = Keypair::new();
+        let (mut context, program_id, pda) = setup(&user, LAMPORTS_PER_SOL, 1_000).await;
+        context.warp_to_slot(100).unwrap();
+        context.get_new_latest_blockhash().await.unwrap();
+
+        withdraw(&mut context, program_id, &user, pda, 100)
+            .await
+            .unwrap();
+        let first_withdraw_slot = stored_deposit(&mut context, pda).await.last_withdraw_slot;
+
+        context
+            .warp_to_slot(first_withdraw_slot + WITHDRAW_COOLDOWN_SLOTS)
+            .unwrap();
+        context.get_new_latest_blockhash().await.unwrap();
+        withdraw(&mut context, program_id, &user, pda, 200)
+            .await
+            .unwrap();
+
+        let deposit = stored_deposit(&mut context, pda).await;
+        assert_eq!(deposit.balance, 700);
+        assert!(deposit.last_withdraw_slot >= first_withdraw_slot + WITHDRAW_COOLDOWN_SLOTS);
+    }
+
+    #[tokio::test]
+    async fn test_deposit_partial_amount() {
+        let user = Keypair::new();
+        let rent_exempt_minimum = Rent::default().minimum_balance(DepositAccount::LEN);
+        let (mut context, program_id, pda) = setup(&user, rent_exempt_minimum, 0).await;
+
+        deposit(&mut context, program_id, &user, pda, 1_000)
+            .await
+            .unwrap();
+
+        // Exactly the requested amount moves, the user keeps the rest
+        assert_eq!(stored_deposit(&mut context, pda).await.balance, 1_000);
+        let pda_account = context
+            .banks_client
+            .get_account(pda)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(pda_account.lamports, rent_exempt_minimum + 1_000);
+        let user_account = context
+            .banks_client
+            .get_account(user.pubkey())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(user_account.lamports, LAMPORTS_PER_SOL - 1_000);
+    }
+
+    #[tokio::test]
+    async fn test_transfer() {
+        let user = Keypair::new();
+        let recipient = Keypair::new();
+        let (mut context, program_id, source, destination) =
+            setup_transfer(&user, &recipient, 1_000).await;
+
+        transfer(&mut context, program_id, &user, source, destination, 400)
+            .await
+            .unwrap();
+
+        // Both the stored balances and the lamports move
+        assert_eq!(stored_deposit(&mut context, source).await.balance, 600);
+        assert_eq!(stored_deposit(&mut context, destination).await.balance, 400);
+        let source_account = context
+            .banks_client
+            .get_account(source)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(source_account.lamports, LAMPORTS_PER_SOL - 400);
+        let destination_account = context
+            .banks_client
+            .get_account(destination)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(destination_account.lamports, LAMPORTS_PER_SOL + 400);
+    }
+
+    #[tokio::test]
+    async fn test_transfer_insufficient_funds_fails() {
+        let user = Keypair::new();
+        let recipient = Keypair::new();
+        let (mut context, program_id, source, destination) =
+            setup_transfer(&user, &recipient, 1_000).await;
+
+        let err = transfer(&mut context, program_id, &user, source, destination, 1_001)
+            .await
+            .unwrap_err()
+            .unwrap();
+        assert_eq!(
+            err,
+            TransactionError::InstructionError(0, InstructionError::InsufficientFunds)
+        );
+
+        assert_eq!(stored_deposit(&mut context, source).await.balance, 1_000);
+        assert_eq!(stored_deposit(&mut context, destination).await.balance, 0);
+    }
+
+    #[tokio::test]
+    async fn test_transfer_by_non_owner_fails() {
+        let user = Keypair::new();
+        let recipient = Keypair::new();
+        let (mut context, program_id, source, destination) =
+            setup_transfer(&user, &recipient, 1_000).await;
+
+        // The recipient can't pull funds out of the user's account
+        let err = transfer(
+            &mut context,
+            program_id,
+            &recipient,
+            source,
+            destination,
+            1_000,
+        )
+        .await
+        .unwrap_err()
+        .unwrap();
+        assert_eq!(
+            err,
+            TransactionError::InstructionError(
+                0,
+                InstructionError::Custom(DepositError::NotOwner as u32)
+            )
+        );
+        assert_eq!(stored_deposit(&mut context, source).await.balance, 1_000);
+    }
+
+    #[tokio::test]
+    async fn test_withdraw_after_transfer_within_cooldown_fails() {
+        let user = Keypair::new();
+        let recipient = Keypair::new();
+        let (mut context, program_id, source, destination) =
+            setup_transfer(&user, &recipient, 1_000).await;
+        context.warp_to_slot(100).unwrap();
+        context.get_new_latest_blockhash().await.unwrap();
+
+        // The transfer starts the cooldown just like a withdrawal
+        transfer(&mut context, program_id, &user, source, destination, 400)
+            .await
+            .unwrap();
+        let err = withdraw(&mut context, program_id, &user, source, 100)
+            .await
+            .unwrap_err()
+            .unwrap();
+        assert_eq!(
+            err,
+            TransactionError::InstructionError(
+                0,
+                InstructionError::Custom(DepositError::WithdrawTooSoon as u32)
+            )
+        );
+
+        let deposit = stored_deposit(&mut context, source).await;
+        assert_eq!(deposit.balance, 600);
+        assert!(deposit.last_withdraw_slot >= 100);
+    }
+
+    #[tokio::test]
+    async fn test_close_account() {
+        let user = Keypair::new();
+        let rent_exempt_minimum = Rent::default().minimum_balance(DepositAccount::LEN);
+        let (mut context, program_id, pda) = setup(&user, rent_exempt_minimum, 0).await;
+
+        close_account(&mut context, program_id, &user, pda)
+            .await
+            .unwrap();
+
+        // The account is gone and its rent went back to the owner
+        assert!(
+            context
+                .banks_client
+                .get_account(pda)
+                .await
+                .unwrap()
+                .is_none()
+        );
+        let user_account = context
+            .banks_client
+            .get_account(user.pubkey())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            user_account.lamports,
+            LAMPORTS_PER_SOL + rent_exempt_minimum
+        );
+    }
+
+    #[tokio::test]
+    async fn test_close_account_wrong_pda_fails() {
+        let user = Keypair::new();
+        let rent_exempt_minimum = Rent::default().minimum_balance(DepositAccount::LEN);
+        // The account lives at the user's PDA but claims to derive from another key
+        let deposit = DepositAccount {
+            balance: 0,
+            owner: user.pubkey(),
+            last_withdraw_slot: 0,
+            seed: Pubkey::new_unique(),
+        };
+        let (mut context, program_id, pda) =
+            setup_with_data(&user, rent_exempt_minimum, borsh::to_vec(&deposit).unwrap()).await;
+
+        let err = close_account(&mut context, program_id, &user, pda)
+            .await
+            .unwrap_err()
+            .unwrap();
+        assert_eq!(
+            err,
+            TransactionError::InstructionError(0, InstructionError::InvalidSeeds)
+        );
+
+        // Nothing was drained
+        let account = context
+            .banks_client
+            .get_account(pda)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(account.lamports, rent_exempt_minimum);
+    }
+
+    #[tokio::test]
+    async fn test_close_account_with_balance_fails() {
+        let user = Keypair::new();
+        let (mut context, program_id, pda) = setup(&user, LAMPORTS_PER_SOL, 1_000).await;
+
+        let err = close_account(&mut context, program_id, &user, pda)
+            .await
+            .unwrap_err()
+            .unwrap();
+        assert_eq!(
+            err,
+            TransactionError::InstructionError(
+                0,
+                InstructionError::Custom(DepositError::BalanceNotZero as u32)
+            )
+        );
+
+        let account = context
+            .banks_client
+            .get_account(pda)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(account.lamports, LAMPORTS_PER_SOL);
+        assert_eq!(stored_deposit(&mut context, pda).await.balance, 1_000);
+    }
+
+    #[tokio::test]
+    async fn test_close_account_by_non_owner_fails() {
+        let user = Keypair::new();
+        let attacker = Keypair::new();
+        let (mut context, program_id, pda) = setup(&user, LAMPORTS_PER_SOL, 0).await;
+
+        let err = close_account(&mut context, program_id, &attacker, pda)
+            .await
+            .unwrap_err()
+            .unwrap();
+        assert_eq!(
+            err,
+            TransactionError::InstructionError(
+                0,
+                InstructionError::Custom(DepositError::NotOwner as u32)
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn test_deposit_into_uninitialized_account_fails() {
+        let user = Keypair::new();
+        let (mut context, program_id, pda) =
+            setup_with_data(&user, LAMPORTS_PER_SOL, vec![0; DepositAccount::LEN]).await;
+
+        let err = deposit(&mut context, program_id, &user, pda, 1_000)
+            .await
+            .unwrap_err()
+            .unwrap();
+        assert_eq!(
+            err,
+            TransactionError::InstructionError(
+                0,
+                InstructionError::Custom(DepositError::AccountNotInitialized as u32)
+            )
+        );
+
+        // Nothing was moved into the account
+        let account = context
+            .banks_client
+            .get_account(pda)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(account.lamports, LAMPORTS_PER_SOL);
+    }
+
+    #[tokio::test]
+    async fn test_deposit_into_short_account_fails() {
+        let user = Keypair::new();
+        let (mut context, program_id, pda) =
+            setup_with_data(&user, LAMPORTS_PER_SOL, vec![1; 4]).await;
+
+        let err = deposit(&mut context, program_id, &user, pda, 1_000)
+            .await
+            .unwrap_err()
+            .unwrap();
+        assert_eq!(
+            err,
+            TransactionError::InstructionError(0, InstructionError::InvalidAccountData)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_withdraw_from_short_account_fails() {
+        let user = Keypair::new();
+        let (mut context, program_id, pda) =
+            setup_with_data(&user, LAMPORTS_PER_SOL, vec![1; 4]).await;
+
+        let err = withdraw(&mut context, program_id, &user, pda, 0)
+            .await
+            .unwrap_err()
+            .unwrap();
+        assert_eq!(
+            err,
+            TransactionError::InstructionError(0, InstructionError::InvalidAccountData)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_withdraw_from_uninitialized_account_fails() {
+        let user = Keypair::new();
+        let (mut context, program_id, pda) =
+            setup_with_data(&user, LAMPORTS_PER_SOL, vec![0; DepositAccount::LEN]).await;
+
+        let err = withdraw(&mut context, program_id, &user, pda, 0)
+            .await
+            .unwrap_err()
+            .unwrap();
+        assert_eq!(
+            err,
+            TransactionError::InstructionError(
+                0,
+                InstructionError::Custom(DepositError::AccountNotInitialized as u32)
+            )
+        );
+    }
+}